@@ -0,0 +1,19 @@
+#import bevy_pbr::{
+    forward_io::VertexOutput,
+    mesh_view_bindings::view,
+}
+
+@group(#{MATERIAL_BIND_GROUP}) @binding(0) var<uniform> units_per_meter: f32;
+
+// Distance along the optical axis as a 24 bit integer, most significant byte in red.
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let view_position = view.view_from_world * vec4(in.world_position.xyz, 1.0);
+    let depth = u32(clamp(-view_position.z * units_per_meter, 0.0, 16777214.0));
+    return vec4(
+        f32((depth >> 16u) & 255u),
+        f32((depth >> 8u) & 255u),
+        f32(depth & 255u),
+        255.0,
+    ) / 255.0;
+}
@@ -0,0 +1,97 @@
+#import bevy_sprite::mesh2d_vertex_output::VertexOutput
+
+struct Lens {
+    image_size: vec2<f32>,
+    focal_length: vec2<f32>,
+    principal_point: vec2<f32>,
+    // k1, k2, k3
+    radial: vec4<f32>,
+    // p1, p2
+    tangential: vec4<f32>,
+}
+
+struct Sensor {
+    // Pixel displacement of the scene during the exposure.
+    blur: vec2<f32>,
+    // Pixel displacement of the scene between the first and last row read out.
+    rolling_shutter: vec2<f32>,
+    gain: f32,
+    noise: f32,
+    seed: f32,
+}
+
+const BLUR_SAMPLES: i32 = 9;
+
+@group(#{MATERIAL_BIND_GROUP}) @binding(0) var<uniform> lens: Lens;
+@group(#{MATERIAL_BIND_GROUP}) @binding(1) var source_texture: texture_2d<f32>;
+@group(#{MATERIAL_BIND_GROUP}) @binding(2) var source_sampler: sampler;
+@group(#{MATERIAL_BIND_GROUP}) @binding(3) var<uniform> sensor: Sensor;
+
+fn distort(p: vec2<f32>) -> vec2<f32> {
+    let k1 = lens.radial.x;
+    let k2 = lens.radial.y;
+    let k3 = lens.radial.z;
+    let p1 = lens.tangential.x;
+    let p2 = lens.tangential.y;
+    let r2 = dot(p, p);
+    let radial = 1.0 + k1 * r2 + k2 * r2 * r2 + k3 * r2 * r2 * r2;
+    return vec2<f32>(
+        p.x * radial + 2.0 * p1 * p.x * p.y + p2 * (r2 + 2.0 * p.x * p.x),
+        p.y * radial + p1 * (r2 + 2.0 * p.y * p.y) + 2.0 * p2 * p.x * p.y,
+    );
+}
+
+// Finds the undistorted normalized coordinate that distorts onto `d` by fixed point iteration.
+fn undistort(d: vec2<f32>) -> vec2<f32> {
+    var p = d;
+    for (var i = 0; i < 8; i++) {
+        let error = distort(p) - d;
+        p = p - error;
+    }
+    return p;
+}
+
+fn hash(p: vec3<u32>) -> f32 {
+    var v = p * 1664525u + 1013904223u;
+    v.x += v.y * v.z;
+    v.y += v.z * v.x;
+    v.z += v.x * v.y;
+    v ^= v >> vec3<u32>(16u);
+    v.x += v.y * v.z;
+    return f32(v.x) / 4294967295.0;
+}
+
+// Standard normal sample using the Box-Muller transform.
+fn gaussian(pixel: vec2<f32>) -> f32 {
+    let seed = u32(sensor.seed * 1000.0);
+    let u1 = max(hash(vec3<u32>(vec2<u32>(pixel), seed)), 1e-7);
+    let u2 = hash(vec3<u32>(vec2<u32>(pixel), seed ^ 0x9E3779B9u));
+    return sqrt(-2.0 * log(u1)) * cos(6.2831853 * u2);
+}
+
+fn sample_ideal(ideal: vec2<f32>) -> vec3<f32> {
+    let uv = ideal / lens.image_size;
+    if any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) {
+        return vec3<f32>(0.0);
+    }
+    return textureSampleLevel(source_texture, source_sampler, uv, 0.0).rgb;
+}
+
+@fragment
+fn fragment(mesh: VertexOutput) -> @location(0) vec4<f32> {
+    let pixel = mesh.uv * lens.image_size;
+    let distorted = (pixel - lens.principal_point) / lens.focal_length;
+    var ideal = undistort(distorted) * lens.focal_length + lens.image_size / 2.0;
+
+    // Rows are read out top to bottom, later rows see the scene further along.
+    ideal += sensor.rolling_shutter * (mesh.uv.y - 0.5);
+
+    var color = vec3<f32>(0.0);
+    for (var i = 0; i < BLUR_SAMPLES; i++) {
+        let t = f32(i) / f32(BLUR_SAMPLES - 1) - 0.5;
+        color += sample_ideal(ideal + sensor.blur * t);
+    }
+    color = color / f32(BLUR_SAMPLES) * sensor.gain;
+    color += vec3<f32>(gaussian(pixel) * sensor.noise);
+    return vec4<f32>(clamp(color, vec3<f32>(0.0), vec3<f32>(1.0)), 1.0);
+}
@@ -1,5 +1,7 @@
+use crate::thermal;
 use bevy::prelude::*;
 use serde::Deserialize;
+use std::f32::consts::PI;
 use std::{error::Error, fs};
 
 #[derive(Resource, Deserialize, Debug, Clone)]
@@ -7,14 +9,689 @@ pub struct Config {
     pub radar_cam_render_width: u32,
     pub radar_cam_render_height: u32,
     pub radar_cam_vertical_fov: f32,
+    #[serde(default = "default_min_fov")]
+    pub radar_cam_min_fov: f32,
+    #[serde(default = "default_max_fov")]
+    pub radar_cam_max_fov: f32,
+    // Degrees per second
+    #[serde(default = "default_zoom_speed")]
+    pub radar_cam_zoom_speed: f32,
     pub radar_cam_x_displacement: f32,
     pub calibrate_panels: (bool, f32),
+    pub scenario_file: Option<String>,
+    pub scene_file: Option<String>,
+    #[serde(default)]
+    pub stream: StreamConfig,
+    pub ground_truth: Option<GroundTruthConfig>,
+    pub overlay: Option<OverlayConfig>,
+    pub lens: Option<LensConfig>,
+    pub sensor: Option<SensorConfig>,
+    pub thermal: Option<ThermalConfig>,
+    pub labels: Option<LabelsConfig>,
+    pub mjpeg: Option<MjpegConfig>,
+    #[serde(default)]
+    pub recording: RecordingConfig,
+    pub metrics: Option<MetricsConfig>,
+    #[serde(default)]
+    pub snapshot: SnapshotConfig,
+    #[serde(default)]
+    pub simulation: SimulationConfig,
+    #[serde(default)]
+    pub environment: EnvironmentConfig,
+    pub terrain: Option<TerrainConfig>,
+    #[serde(default)]
+    pub cameras: Vec<CameraConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct StreamConfig {
+    // Frames are still rendered and captured for labels, ground truth and MJPEG when false
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub encoder: Encoder,
+    #[serde(default)]
+    pub codec: Codec,
+    // Kilobits per second, the encoder default when unset
+    pub bitrate: Option<u32>,
+    // Frames between keyframes, one second when unset
+    pub gop: Option<u32>,
+    // Radar camera frame rate, deterministic ticks run at the fastest camera rate
+    #[serde(default = "default_frame_rate")]
+    pub frame_rate: u32,
+    // ffmpeg pixel format, yuv420p (yuvj420p for MJPEG) when unset
+    pub pixel_format: Option<String>,
+    // RTSP transport ffmpeg publishes with
+    #[serde(default)]
+    pub transport: Transport,
+    // Radar camera output, other cameras replace the last path segment with their stream_path
+    #[serde(default = "default_stream_url")]
+    pub url: String,
+    // Embeds az/el/fov/time as MISB 0601 KLV in H.264 SEI messages
+    #[serde(default)]
+    pub metadata: bool,
+    #[serde(default)]
+    pub rtsp_server: RtspServer,
+    #[serde(default = "default_native_port")]
+    #[cfg_attr(not(feature = "native-encoder"), allow(dead_code))]
+    pub native_port: u16,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            encoder: Encoder::default(),
+            codec: Codec::default(),
+            bitrate: None,
+            gop: None,
+            frame_rate: default_frame_rate(),
+            pixel_format: None,
+            transport: Transport::default(),
+            url: default_stream_url(),
+            metadata: false,
+            rtsp_server: RtspServer::default(),
+            native_port: default_native_port(),
+        }
+    }
+}
+
+impl StreamConfig {
+    // Stream path of the radar camera, the last segment of the url.
+    pub fn path(&self) -> &str {
+        self.url.rsplit('/').next().unwrap_or_default()
+    }
+
+    // The url with its last segment replaced by path.
+    pub fn url_for(&self, path: &str) -> String {
+        match self.url.rsplit_once('/') {
+            Some((base, _)) => format!("{}/{}", base, path),
+            None => path.to_string(),
+        }
+    }
+
+    // Host of the url, this machine when it has none.
+    #[cfg_attr(not(feature = "rtsp-server"), allow(dead_code))]
+    pub fn host(&self) -> &str {
+        self.url
+            .split("://")
+            .nth(1)
+            .and_then(|rest| rest.split('/').next())
+            .map(|authority| match authority.rsplit_once(':') {
+                // An IPv6 address without a port has colons of its own.
+                Some((host, port)) if !port.contains(']') => host,
+                _ => authority,
+            })
+            .filter(|host| !host.is_empty())
+            .unwrap_or("127.0.0.1")
+    }
+
+    // Port of the url, the RTSP default when it has none.
+    #[cfg_attr(not(feature = "rtsp-server"), allow(dead_code))]
+    pub fn port(&self) -> u16 {
+        self.url
+            .split("://")
+            .nth(1)
+            .and_then(|rest| rest.split('/').next())
+            .and_then(|host| host.rsplit_once(':'))
+            .and_then(|(_, port)| port.parse().ok())
+            .unwrap_or(8554)
+    }
+
+    // Whether the url points at an RTSP server on this machine.
+    pub fn is_local_rtsp(&self) -> bool {
+        ["rtsp://127.0.0.1", "rtsp://localhost", "rtsp://0.0.0.0"]
+            .iter()
+            .any(|prefix| self.url.starts_with(prefix))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    #[default]
+    H264,
+    H265,
+    Mjpeg,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    #[default]
+    Udp,
+    Tcp,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoder {
+    // ffmpeg and mediamtx next to the executable, streams over RTSP
+    #[default]
+    Ffmpeg,
+    // In process H.264 encoder, streams over HTTP
+    Native,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RtspServer {
+    // mediamtx next to the executable on port 8554
+    #[default]
+    Mediamtx,
+    // In process server on the port of the stream url
+    Builtin,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GroundTruthConfig {
+    pub port: u16,
+    pub file: Option<String>,
 }
 
 impl Config {
     pub fn from_file(path: &str) -> Result<Self, Box<dyn Error>> {
         let config_str = fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&config_str)?;
+        let mut config: Config = toml::from_str(&config_str)?;
+        // The seed used to be set in [simulation].
+        if let Some(seed) = config.simulation.seed.take() {
+            if config
+                .environment
+                .seed
+                .is_some_and(|environment| environment != seed)
+            {
+                return Err("seed is set differently in [simulation] and [environment]".into());
+            }
+            config.environment.seed = Some(seed);
+        }
+        let stream = &config.stream;
+        if stream.encoder == Encoder::Native && !cfg!(feature = "native-encoder") {
+            return Err(
+                "encoder = \"native\" needs a build with the native-encoder feature".into(),
+            );
+        }
+        if stream.rtsp_server == RtspServer::Builtin && !cfg!(feature = "rtsp-server") {
+            return Err(
+                "rtsp_server = \"builtin\" needs a build with the rtsp-server feature".into(),
+            );
+        }
+        let h264_only = stream.encoder == Encoder::Native
+            || stream.rtsp_server == RtspServer::Builtin
+            || stream.metadata;
+        if h264_only && stream.codec != Codec::H264 {
+            return Err(
+                "the native encoder, builtin RTSP server and metadata need codec = \"h264\"".into(),
+            );
+        }
+        if stream.frame_rate == 0 {
+            return Err("stream frame_rate must be above 0".into());
+        }
+        let mut paths = vec![stream.path()];
+        for camera in &config.cameras {
+            if camera.frame_rate == 0 {
+                return Err(format!("camera {} frame_rate must be above 0", camera.name).into());
+            }
+            if camera.width == 0 || camera.height == 0 {
+                return Err(
+                    format!("camera {} width and height must be above 0", camera.name).into(),
+                );
+            }
+            if !(camera.vertical_fov > 0.0 && camera.vertical_fov < 180.0) {
+                return Err(format!(
+                    "camera {} vertical_fov must be between 0 and 180",
+                    camera.name
+                )
+                .into());
+            }
+            // Feeds are served and counted by path, a second one would hide the first.
+            if paths.contains(&camera.stream_path.as_str()) {
+                return Err(format!(
+                    "camera {} stream_path {} is already used by another stream",
+                    camera.name, camera.stream_path
+                )
+                .into());
+            }
+            paths.push(&camera.stream_path);
+        }
+        let (min_fov, max_fov) = (config.radar_cam_min_fov, config.radar_cam_max_fov);
+        if !(min_fov > 0.0 && min_fov <= max_fov && max_fov < 180.0) {
+            return Err(
+                "radar_cam_min_fov and radar_cam_max_fov must satisfy 0 < min <= max < 180".into(),
+            );
+        }
+        if !(min_fov..=max_fov).contains(&config.radar_cam_vertical_fov) {
+            return Err(format!(
+                "radar_cam_vertical_fov must be between radar_cam_min_fov ({}) and radar_cam_max_fov ({})",
+                min_fov, max_fov
+            )
+            .into());
+        }
+        let zoom_speed = config.radar_cam_zoom_speed;
+        if !zoom_speed.is_finite() || zoom_speed <= 0.0 {
+            return Err("radar_cam_zoom_speed must be above 0".into());
+        }
+        let environment = &config.environment;
+        let (inner_radius, radius) = (environment.inner_radius, environment.radius);
+        if !inner_radius.is_finite() || !radius.is_finite() || inner_radius < 0.0 {
+            return Err("environment inner_radius and radius must be finite and at least 0".into());
+        }
+        if radius < inner_radius {
+            return Err("environment radius must be at least inner_radius".into());
+        }
+        if !(0.0..=1.0).contains(&environment.variety) {
+            return Err("environment variety must be between 0 and 1".into());
+        }
+        if let Some(terrain) = &config.terrain {
+            if !terrain.cell_size.is_finite() || terrain.cell_size <= 0.0 {
+                return Err("terrain cell_size must be above 0".into());
+            }
+        }
+        let densities = [environment.tree_density, environment.house_density];
+        if densities
+            .iter()
+            .flatten()
+            .any(|density| !density.is_finite() || *density < 0.0)
+        {
+            return Err("environment tree_density and house_density must be at least 0".into());
+        }
+        if environment.tree_count() > MAX_OBJECTS || environment.house_count() > MAX_OBJECTS {
+            return Err(format!(
+                "environment can place at most {} trees and houses each",
+                MAX_OBJECTS
+            )
+            .into());
+        }
         Ok(config)
     }
 }
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OverlayConfig {
+    #[serde(default = "enabled")]
+    pub crosshair: bool,
+    #[serde(default = "enabled")]
+    pub telemetry: bool,
+    #[serde(default = "enabled")]
+    pub heading_tape: bool,
+    #[serde(default = "default_font_size")]
+    pub font_size: f32,
+    #[serde(default = "default_overlay_color")]
+    pub color: [f32; 3],
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LensConfig {
+    // Pixels from the image center
+    #[serde(default)]
+    pub principal_point_offset: [f32; 2],
+    // k1, k2, k3
+    #[serde(default)]
+    pub radial: [f32; 3],
+    // p1, p2
+    #[serde(default)]
+    pub tangential: [f32; 2],
+    pub intrinsics_file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SensorConfig {
+    // Standard deviation of the added noise, 0 to 1
+    #[serde(default)]
+    pub noise: f32,
+    #[serde(default)]
+    pub auto_exposure: bool,
+    // Mean brightness auto exposure aims for, 0 to 1
+    #[serde(default = "default_exposure_target")]
+    pub exposure_target: f32,
+    // Seconds, drives motion blur
+    #[serde(default)]
+    pub exposure_time: f32,
+    // Seconds from the first to the last row, drives rolling shutter skew
+    #[serde(default)]
+    pub readout_time: f32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ThermalConfig {
+    #[serde(default)]
+    pub palette: thermal::Palette,
+    // Degrees Celsius mapped to black and white
+    pub min_temperature: f32,
+    pub max_temperature: f32,
+    // Temperature of everything without a Temperature
+    pub ambient_temperature: f32,
+}
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        Self {
+            palette: thermal::Palette::WhiteHot,
+            min_temperature: -10.0,
+            max_temperature: 60.0,
+            ambient_temperature: 12.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MjpegConfig {
+    #[serde(default = "default_mjpeg_port")]
+    pub port: u16,
+    // JPEG quality, 1 to 100
+    #[serde(default = "default_mjpeg_quality")]
+    pub quality: u8,
+    #[serde(default = "default_mjpeg_frame_rate")]
+    pub frame_rate: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MetricsConfig {
+    #[serde(default = "default_metrics_port")]
+    pub port: u16,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SimulationConfig {
+    // Advance by fixed steps on STEP commands only
+    #[serde(default)]
+    pub deterministic: bool,
+    // Unix time in seconds of the first tick in deterministic mode
+    #[serde(default)]
+    pub start_time: f64,
+    // Old place of the environment seed, moved there when loading
+    pub seed: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EnvironmentConfig {
+    // Seed for placing the trees and houses, fixed to 0 in deterministic mode when not set
+    pub seed: Option<u64>,
+    #[serde(default = "default_tree_count")]
+    pub trees: usize,
+    #[serde(default = "default_house_count")]
+    pub houses: usize,
+    // Objects per square kilometre of the area, used instead of the counts when set
+    pub tree_density: Option<f32>,
+    pub house_density: Option<f32>,
+    // Objects are placed between inner_radius and radius metres from the pedestal
+    #[serde(default = "default_environment_inner_radius")]
+    pub inner_radius: f32,
+    #[serde(default = "default_environment_radius")]
+    pub radius: f32,
+    #[serde(default)]
+    pub exclusion_zones: Vec<ExclusionZone>,
+    // How much the size, heading and colour of the objects vary, from 0 to 1
+    #[serde(default)]
+    pub variety: f32,
+}
+
+// Trees or houses the environment may place, more would stall the start.
+pub const MAX_OBJECTS: usize = 10_000;
+
+impl EnvironmentConfig {
+    pub fn tree_count(&self) -> usize {
+        self.object_count(self.trees, self.tree_density)
+    }
+
+    pub fn house_count(&self) -> usize {
+        self.object_count(self.houses, self.house_density)
+    }
+
+    // The configured count, or the density over the area between the radii.
+    fn object_count(&self, count: usize, density: Option<f32>) -> usize {
+        match density {
+            Some(density) => {
+                let area = PI * (self.radius.powi(2) - self.inner_radius.powi(2));
+                (density.max(0.0) * area / 1e6).round() as usize
+            }
+            None => count,
+        }
+    }
+}
+
+impl Default for EnvironmentConfig {
+    fn default() -> Self {
+        Self {
+            seed: None,
+            trees: default_tree_count(),
+            houses: default_house_count(),
+            tree_density: None,
+            house_density: None,
+            inner_radius: default_environment_inner_radius(),
+            radius: default_environment_radius(),
+            exclusion_zones: Vec::new(),
+            variety: 0.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TerrainConfig {
+    // Heightmap PNG, GeoTIFF (.tif) or ASCII grid (.asc) DEM
+    pub file: String,
+    // Metres between the pixels of a heightmap PNG, DEMs bring their own
+    #[serde(default = "default_terrain_cell_size")]
+    pub cell_size: f64,
+    // Heights of black and white in a heightmap PNG
+    #[serde(default)]
+    pub min_height: f32,
+    #[serde(default = "default_terrain_max_height")]
+    pub max_height: f32,
+    // Pedestal position in the map coordinates of the DEM, the centre when not set. The
+    // lower left corner of a heightmap PNG is at [0, 0].
+    pub location: Option<[f64; 2]>,
+    // Elevation of the pedestal base, the ground at its location when not set
+    pub height: Option<f32>,
+}
+
+// A circle on the ground, x and z in metres, kept clear of trees and houses.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ExclusionZone {
+    pub center: [f32; 2],
+    pub radius: f32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SnapshotConfig {
+    #[serde(default = "default_snapshot_directory")]
+    pub directory: String,
+    #[serde(default)]
+    pub format: ImageFormat,
+    // JPEG quality, 1 to 100
+    #[serde(default = "default_snapshot_quality")]
+    pub quality: u8,
+    // Serves snapshots over HTTP when set
+    pub port: Option<u16>,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            directory: default_snapshot_directory(),
+            format: ImageFormat::default(),
+            quality: default_snapshot_quality(),
+            port: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    Jpeg,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RecordingConfig {
+    #[serde(default = "default_recording_directory")]
+    pub directory: String,
+    #[serde(default)]
+    pub container: Container,
+    #[serde(default)]
+    pub telemetry: TelemetryFormat,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            directory: default_recording_directory(),
+            container: Container::default(),
+            telemetry: TelemetryFormat::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Container {
+    #[default]
+    Mp4,
+    Mkv,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TelemetryFormat {
+    #[default]
+    Csv,
+    Jsonl,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LabelsConfig {
+    #[serde(default = "enabled")]
+    pub depth: bool,
+    #[serde(default = "enabled")]
+    pub segmentation: bool,
+    #[serde(default = "default_labels_directory")]
+    pub directory: String,
+    // Write labels for every n-th streamed frame
+    #[serde(default = "default_every_n_frames")]
+    pub every_n_frames: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CameraKind {
+    #[default]
+    Visible,
+    Thermal,
+}
+
+// An additional sensor on the pedestal next to the radar camera.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CameraConfig {
+    pub name: String,
+    #[serde(default)]
+    pub kind: CameraKind,
+    pub width: u32,
+    pub height: u32,
+    pub vertical_fov: f32,
+    // Position on the pedestal pivot
+    pub mount_offset: [f32; 3],
+    #[serde(default = "default_frame_rate")]
+    pub frame_rate: u32,
+    pub stream_path: String,
+}
+
+fn default_stream_url() -> String {
+    "rtsp://127.0.0.1:8554/live".to_string()
+}
+
+fn default_native_port() -> u16 {
+    8555
+}
+
+fn default_frame_rate() -> u32 {
+    25
+}
+
+fn default_mjpeg_port() -> u16 {
+    8080
+}
+
+fn default_mjpeg_quality() -> u8 {
+    80
+}
+
+fn default_mjpeg_frame_rate() -> u32 {
+    10
+}
+
+fn default_metrics_port() -> u16 {
+    9464
+}
+
+fn default_tree_count() -> usize {
+    75
+}
+
+fn default_house_count() -> usize {
+    25
+}
+
+fn default_environment_inner_radius() -> f32 {
+    5.0
+}
+
+fn default_environment_radius() -> f32 {
+    50.0
+}
+
+fn default_terrain_cell_size() -> f64 {
+    1.0
+}
+
+fn default_terrain_max_height() -> f32 {
+    100.0
+}
+
+fn default_snapshot_directory() -> String {
+    "snapshots".to_string()
+}
+
+fn default_snapshot_quality() -> u8 {
+    90
+}
+
+fn default_recording_directory() -> String {
+    "recordings".to_string()
+}
+
+fn default_labels_directory() -> String {
+    "labels".to_string()
+}
+
+fn default_every_n_frames() -> u32 {
+    1
+}
+
+fn default_exposure_target() -> f32 {
+    0.45
+}
+
+fn default_min_fov() -> f32 {
+    2.0
+}
+
+fn default_max_fov() -> f32 {
+    45.0
+}
+
+fn default_zoom_speed() -> f32 {
+    10.0
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn default_font_size() -> f32 {
+    28.0
+}
+
+fn default_overlay_color() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
@@ -1,4 +1,8 @@
 use crate::config;
+use crate::scene;
+use crate::simulation;
+use crate::terrain;
+use crate::thermal::Temperature;
 use bevy::color::palettes::css::LIGHT_GREEN;
 use bevy::prelude::*;
 use bevy_panorbit_camera::PanOrbitCamera;
@@ -12,7 +16,7 @@ pub fn spawn_env(
     commands: &mut Commands,
     meshes: &mut Assets<Mesh>,
     materials: &mut Assets<StandardMaterial>,
-    asset_server: Res<AssetServer>,
+    asset_server: &AssetServer,
     config: &Res<config::Config>,
 ) {
     commands.insert_resource(AmbientLight {
@@ -38,26 +42,83 @@ pub fn spawn_env(
             ..default()
         })),
         Transform::default().with_scale(Vec3::splat(-4000.0)),
+        Temperature(-30.0),
     ));
 
-    commands.spawn((
-        Mesh3d(
-            meshes.add(
-                Plane3d::default()
-                    .mesh()
-                    .size(5000.0, 5000.0)
-                    .subdivisions(10),
+    let scene = config
+        .scene_file
+        .as_ref()
+        .and_then(|file| match scene::Scene::from_file(file) {
+            Ok(scene) => Some(scene),
+            Err(e) => {
+                eprintln!("Failed to load scene {}: {}", file, e);
+                None
+            }
+        });
+    let builtin = scene.as_ref().is_none_or(|scene| scene.builtin);
+    if let Some(scene) = &scene {
+        scene::spawn_scene(commands, asset_server, scene);
+    }
+
+    let terrain = config.terrain.as_ref().and_then(|terrain_config| {
+        match terrain::Terrain::load(terrain_config) {
+            Ok(terrain) => Some(terrain),
+            Err(e) => {
+                eprintln!("Failed to load terrain {}: {}", terrain_config.file, e);
+                None
+            }
+        }
+    });
+    let ground_mat = materials.add(Color::from(LIGHT_GREEN));
+    if let Some(terrain) = &terrain {
+        commands.spawn((
+            Mesh3d(meshes.add(terrain.mesh())),
+            MeshMaterial3d(ground_mat.clone()),
+            Transform::default(),
+            Temperature(14.0),
+        ));
+        println!(
+            "Spawned terrain from {}",
+            config.terrain.as_ref().unwrap().file
+        );
+    }
+
+    if builtin {
+        // Below the terrain, reaching past its edges.
+        let ground = terrain
+            .as_ref()
+            .map_or(0.0, |terrain| terrain.lowest() - 0.1);
+        commands.spawn((
+            Mesh3d(
+                meshes.add(
+                    Plane3d::default()
+                        .mesh()
+                        .size(5000.0, 5000.0)
+                        .subdivisions(10),
+                ),
             ),
-        ),
-        MeshMaterial3d(materials.add(Color::from(LIGHT_GREEN))),
-        Transform::from_xyz(0.0, 0.0, 0.0),
-    ));
+            MeshMaterial3d(ground_mat),
+            Transform::from_xyz(0.0, ground, 0.0),
+            Temperature(14.0),
+        ));
+    }
 
     if config.calibrate_panels.0 {
-        spawn_calibration_panels(commands, meshes, materials, asset_server, &config);
-    } else {
-        spawn_trees(meshes, materials, commands);
-        spawn_houses(meshes, materials, commands, asset_server);
+        spawn_calibration_panels(commands, meshes, materials, asset_server, config);
+    } else if builtin {
+        let mut rng = simulation::rng(config);
+        let environment = &config.environment;
+        let terrain = terrain.as_ref();
+        spawn_trees(meshes, materials, commands, environment, terrain, &mut rng);
+        spawn_houses(
+            meshes,
+            materials,
+            commands,
+            asset_server,
+            environment,
+            terrain,
+            &mut rng,
+        );
     }
 
     commands.spawn((
@@ -74,70 +135,136 @@ pub fn spawn_env(
     ));
 }
 
+// Shades of each material picked from when the objects vary.
+const SHADES: usize = 4;
+// Tries at finding a spot outside the exclusion zones before an object is left out.
+const PLACEMENT_ATTEMPTS: usize = 100;
+
 fn spawn_trees(
     meshes: &mut Assets<Mesh>,
     materials: &mut Assets<StandardMaterial>,
     commands: &mut Commands,
+    environment: &config::EnvironmentConfig,
+    terrain: Option<&terrain::Terrain>,
+    rng: &mut impl Rng,
 ) {
-    const N_TREES: usize = 75;
     let trunk = meshes.add(Cylinder::default());
     let crown = meshes.add(Sphere::default());
     let trunk_mat = materials.add(Color::linear_rgb(0.4, 0.2, 0.2));
-    let crown_mat = materials.add(Color::linear_rgb(0.0, 1.0, 0.0));
+    let crown_mats: Vec<_> = (0..SHADES)
+        .map(|i| materials.add(Color::linear_rgb(0.0, 1.0 - 0.15 * i as f32, 0.0)))
+        .collect();
 
-    for _i in 0..N_TREES {
-        let x = rnd_tree_coord();
-        let z = rnd_tree_coord();
+    let variety = environment.variety;
+    for _i in 0..environment.tree_count() {
+        let Some(spot) = random_spot(environment, rng) else {
+            continue;
+        };
+        let height = vary(rng, variety);
+        let width = vary(rng, variety);
+        let shade = random_shade(rng, variety);
+        let ground = ground_height(terrain, spot.x, spot.y);
         commands.spawn((
             Mesh3d(trunk.clone()),
             MeshMaterial3d(trunk_mat.clone()),
-            Transform::from_xyz(x, 0.0, z).with_scale(Vec3::new(0.1, 1.0, 0.1)),
+            Transform::from_xyz(spot.x, ground, spot.y).with_scale(Vec3::new(
+                0.1 * width,
+                height,
+                0.1 * width,
+            )),
+            Temperature(11.0),
         ));
         commands.spawn((
             Mesh3d(crown.clone()),
-            MeshMaterial3d(crown_mat.clone()),
-            Transform::from_xyz(x, 1.0, z),
+            MeshMaterial3d(crown_mats[shade].clone()),
+            Transform::from_xyz(spot.x, ground + height, spot.y).with_scale(Vec3::splat(width)),
+            Temperature(9.0),
         ));
     }
 }
 
-fn rnd_tree_coord() -> f32 {
-    let mut rng = rand::rng();
-    let random_number: f32 = if rng.random_bool(0.5) {
-        rng.random_range(-50.0..=-5.0)
+// Height of the ground at x, z, flat at 0 without terrain.
+fn ground_height(terrain: Option<&terrain::Terrain>, x: f32, z: f32) -> f32 {
+    terrain.map_or(0.0, |terrain| terrain.height_at(x, z))
+}
+
+// A spot spread evenly over the area between the radii and outside the exclusion zones,
+// None when none was found.
+fn random_spot(environment: &config::EnvironmentConfig, rng: &mut impl Rng) -> Option<Vec2> {
+    let inner = environment.inner_radius.powi(2);
+    let outer = environment.radius.powi(2);
+    (0..PLACEMENT_ATTEMPTS)
+        .map(|_| {
+            let distance = rng.random_range(inner..=outer).sqrt();
+            Vec2::from_angle(rng.random_range(-PI..PI)) * distance
+        })
+        .find(|spot| {
+            environment
+                .exclusion_zones
+                .iter()
+                .all(|zone| spot.distance(Vec2::from(zone.center)) >= zone.radius)
+        })
+}
+
+// Scale factor around 1, up to 50% off at full variety.
+fn vary(rng: &mut impl Rng, variety: f32) -> f32 {
+    1.0 + rng.random_range(-0.5..=0.5) * variety
+}
+
+fn random_shade(rng: &mut impl Rng, variety: f32) -> usize {
+    if variety > 0.0 {
+        rng.random_range(0..SHADES)
     } else {
-        rng.random_range(5.0..=50.0)
-    };
-    random_number
+        0
+    }
 }
 
 fn spawn_houses(
     meshes: &mut Assets<Mesh>,
     materials: &mut Assets<StandardMaterial>,
     commands: &mut Commands,
-    asset_server: Res<AssetServer>,
+    asset_server: &AssetServer,
+    environment: &config::EnvironmentConfig,
+    terrain: Option<&terrain::Terrain>,
+    rng: &mut impl Rng,
 ) {
     let house_body = meshes.add(Cuboid::default());
     let window = meshes.add(Cuboid {
         half_size: Vec3::new(0.21, 0.21, 0.51),
         ..default()
     });
-    let house_body_mat = materials.add(Color::linear_rgb(0.5, 0.5, 0.5));
+    let house_body_mats: Vec<_> = (0..SHADES)
+        .map(|i| {
+            let grey = 0.5 + 0.1 * i as f32;
+            materials.add(Color::linear_rgb(grey, grey, grey))
+        })
+        .collect();
     let window_mat = materials.add(Color::linear_rgb(0.0, 0.0, 0.0));
 
-    const N_HOUSES: usize = 25;
-    for _i in 0..N_HOUSES {
-        let x = rnd_tree_coord();
-        let z = rnd_tree_coord();
+    let variety = environment.variety;
+    for _i in 0..environment.house_count() {
+        let Some(spot) = random_spot(environment, rng) else {
+            continue;
+        };
+        let size = Vec3::new(vary(rng, variety), vary(rng, variety), vary(rng, variety));
+        let heading = Quat::from_rotation_y(rng.random_range(-PI..PI) * variety);
+        let shade = random_shade(rng, variety);
+        let ground = ground_height(terrain, spot.x, spot.y);
         commands.spawn((
             Mesh3d(house_body.clone()),
-            MeshMaterial3d(house_body_mat.clone()),
-            Transform::from_xyz(x, 0.5, z),
+            MeshMaterial3d(house_body_mats[shade].clone()),
+            Transform::from_xyz(spot.x, ground + 0.5 * size.y, spot.y)
+                .with_rotation(heading)
+                .with_scale(size),
+            Temperature(18.0),
         ));
         commands.spawn((
             Mesh3d(window.clone()),
             MeshMaterial3d(window_mat.clone()),
-            Transform::from_xyz(x, 0.6, z),
+            Transform::from_xyz(spot.x, ground + 0.6 * size.y, spot.y)
+                .with_rotation(heading)
+                .with_scale(Vec3::new(1.0, 1.0, size.z)),
+            Temperature(24.0),
         ));
     }
 
@@ -147,11 +274,13 @@ fn spawn_houses(
     );
     commands.spawn((
         SceneRoot(scene_handle1.clone()),
-        Transform::from_xyz(7.0, 0.0, -14.0),
+        Transform::from_xyz(7.0, ground_height(terrain, 7.0, -14.0), -14.0),
+        Temperature(20.0),
     ));
     commands.spawn((
         SceneRoot(scene_handle1.clone()),
-        Transform::from_xyz(-7.0, 0.0, 14.0),
+        Transform::from_xyz(-7.0, ground_height(terrain, -7.0, 14.0), 14.0),
+        Temperature(20.0),
     ));
 
     let spike_house = meshes.add(Cone {
@@ -163,12 +292,13 @@ fn spawn_houses(
     commands.spawn((
         Mesh3d(spike_house.clone()),
         MeshMaterial3d(spike_house_mat.clone()),
-        Transform::from_xyz(-6.0, 0.0, -12.0),
+        Transform::from_xyz(-6.0, ground_height(terrain, -6.0, -12.0), -12.0),
     ));
     commands.spawn((
         Mesh3d(spike_house.clone()),
         MeshMaterial3d(spike_house_mat.clone()),
-        Transform::from_xyz(6.0, 0.0, 18.0).with_scale(Vec3::new(1.0, 5.0, 1.0)),
+        Transform::from_xyz(6.0, ground_height(terrain, 6.0, 18.0), 18.0)
+            .with_scale(Vec3::new(1.0, 5.0, 1.0)),
     ));
 }
 
@@ -176,7 +306,7 @@ fn spawn_calibration_panels(
     commands: &mut Commands,
     meshes: &mut Assets<Mesh>,
     materials: &mut Assets<StandardMaterial>,
-    asset_server: Res<AssetServer>,
+    asset_server: &AssetServer,
     config: &Res<config::Config>,
 ) {
     let texture_handle = asset_server.load("calibration-checkerboard.png");
@@ -0,0 +1,221 @@
+use crate::config;
+use crate::metrics;
+use crate::post_process;
+use crate::radar_cam;
+use crate::scenario;
+use crate::stream;
+use bevy::camera::primitives::Aabb;
+use bevy::prelude::*;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Read by the metrics without locking the clients the writer is busy with.
+static CONNECTED_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Resource)]
+pub struct GroundTruthPublisher {
+    tx: Sender<String>,
+}
+
+#[derive(Serialize)]
+struct FrameTruth<'a> {
+    frame: u64,
+    time: f64,
+    width: u32,
+    height: u32,
+    targets: Vec<TargetTruth<'a>>,
+}
+
+#[derive(Serialize)]
+struct TargetTruth<'a> {
+    name: &'a str,
+    class: &'a str,
+    azimuth: f32,
+    elevation: f32,
+    range: f32,
+    // Pixel bounding box [x_min, y_min, x_max, y_max] clipped to the image, None when not in view.
+    bbox: Option<[f32; 4]>,
+}
+
+pub fn start_publisher(commands: &mut Commands, config: &config::GroundTruthConfig) {
+    let (tx, rx) = mpsc::channel::<String>();
+    let clients = Arc::new(Mutex::new(Vec::<TcpStream>::new()));
+
+    let address = format!("127.0.0.1:{}", config.port);
+    let listener_clients = clients.clone();
+    metrics::register_clients("ground_truth", || CONNECTED_CLIENTS.load(Ordering::Relaxed));
+    thread::spawn(move || {
+        let listener = TcpListener::bind(&address).expect("Could not bind ground truth address");
+        println!("Ground truth listener running on {}", address);
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    listener_clients.lock().unwrap().push(stream);
+                    CONNECTED_CLIENTS.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => eprintln!("Failed to accept a ground truth connection: {:?}", e),
+            }
+        }
+    });
+
+    let file = config.file.as_ref().map(|path| {
+        BufWriter::new(File::create(path).expect("Failed to create ground truth file"))
+    });
+    thread::spawn(move || {
+        run_writer(rx, file, clients);
+    });
+
+    commands.insert_resource(GroundTruthPublisher { tx });
+}
+
+fn run_writer(
+    rx: Receiver<String>,
+    mut file: Option<BufWriter<File>>,
+    clients: Arc<Mutex<Vec<TcpStream>>>,
+) {
+    while let Ok(line) = rx.recv() {
+        if let Some(file) = file.as_mut() {
+            if let Err(e) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+                eprintln!("Failed to write ground truth file: {}", e);
+            }
+        }
+        let mut clients = clients.lock().unwrap();
+        clients.retain_mut(|client| {
+            let connected = writeln!(client, "{}", line).is_ok();
+            if !connected {
+                CONNECTED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+            }
+            connected
+        });
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn publish_ground_truth(
+    publisher: Res<GroundTruthPublisher>,
+    stream_query: Query<&stream::CameraStream>,
+    time: Res<Time>,
+    lens: Res<post_process::LensModel>,
+    zoom: Res<radar_cam::Zoom>,
+    camera_query: Query<(Entity, &Camera, &GlobalTransform), With<radar_cam::RadarCamera>>,
+    target_query: Query<(Entity, &scenario::Target)>,
+    children_query: Query<&Children>,
+    bounds_query: Query<(&Aabb, &GlobalTransform)>,
+    // Radar camera frame the truth was last published for
+    mut published: Local<u64>,
+) {
+    let Ok((camera_entity, camera, camera_transform)) = camera_query.single() else {
+        return;
+    };
+    let Some(stream) = stream_query.iter().find(|s| s.camera == camera_entity) else {
+        return;
+    };
+    // Only ticks that streamed a new frame get their truth.
+    if stream.frame_number == *published {
+        return;
+    }
+    *published = stream.frame_number;
+    let Some(size) = camera.physical_target_size() else {
+        return;
+    };
+    let view_from_world = camera_transform.affine().inverse();
+    let origin = camera_transform.translation();
+
+    let mut targets = Vec::new();
+    for (entity, target) in target_query.iter() {
+        let mut corners = Vec::new();
+        for descendant in std::iter::once(entity).chain(children_query.iter_descendants(entity)) {
+            if let Ok((aabb, transform)) = bounds_query.get(descendant) {
+                corners.extend(aabb_corners(aabb).map(|c| transform.transform_point(c)));
+            }
+        }
+        if corners.is_empty() {
+            // Bounds are not computed until the mesh or scene has loaded.
+            continue;
+        }
+        let (min, max) = corners
+            .iter()
+            .fold((Vec3::MAX, Vec3::MIN), |(min, max), c| {
+                (min.min(*c), max.max(*c))
+            });
+        let d = (min + max) / 2.0 - origin;
+        let range = d.length();
+        let azimuth = d.x.atan2(-d.z).to_degrees().rem_euclid(360.0);
+        let elevation = d.y.atan2(d.xz().length()).to_degrees();
+
+        targets.push(TargetTruth {
+            name: &target.name,
+            class: &target.class,
+            azimuth,
+            elevation,
+            range,
+            bbox: project_bbox(camera, &view_from_world, &corners, size, |pixel| {
+                lens.distort_pixel(pixel, zoom.current_fov)
+            }),
+        });
+    }
+
+    let truth = FrameTruth {
+        frame: stream.frame_number,
+        time: time.elapsed_secs_f64(),
+        width: size.x,
+        height: size.y,
+        targets,
+    };
+    match serde_json::to_string(&truth) {
+        Ok(line) => {
+            let _ = publisher.tx.send(line);
+        }
+        Err(e) => eprintln!("Failed to serialize ground truth: {}", e),
+    }
+}
+
+fn aabb_corners(aabb: &Aabb) -> [Vec3; 8] {
+    let c = Vec3::from(aabb.center);
+    let h = Vec3::from(aabb.half_extents);
+    [
+        c + Vec3::new(-h.x, -h.y, -h.z),
+        c + Vec3::new(h.x, -h.y, -h.z),
+        c + Vec3::new(-h.x, h.y, -h.z),
+        c + Vec3::new(h.x, h.y, -h.z),
+        c + Vec3::new(-h.x, -h.y, h.z),
+        c + Vec3::new(h.x, -h.y, h.z),
+        c + Vec3::new(-h.x, h.y, h.z),
+        c + Vec3::new(h.x, h.y, h.z),
+    ]
+}
+
+fn project_bbox(
+    camera: &Camera,
+    view_from_world: &bevy::math::Affine3A,
+    corners: &[Vec3],
+    size: UVec2,
+    distort: impl Fn(Vec2) -> Vec2,
+) -> Option<[f32; 4]> {
+    let (w, h) = (size.x as f32, size.y as f32);
+    let mut min = Vec2::MAX;
+    let mut max = Vec2::MIN;
+    for corner in corners {
+        let view_point = view_from_world.transform_point3(*corner);
+        // Only boxes fully in front of the camera are reported, the camera looks down -Z.
+        if view_point.z >= 0.0 {
+            return None;
+        }
+        let ndc = camera.clip_from_view().project_point3(view_point);
+        let pixel = distort(Vec2::new((ndc.x + 1.0) / 2.0 * w, (1.0 - ndc.y) / 2.0 * h));
+        min = min.min(pixel);
+        max = max.max(pixel);
+    }
+    let min = min.max(Vec2::ZERO);
+    let max = max.min(Vec2::new(w, h));
+    if min.x >= max.x || min.y >= max.y {
+        return None;
+    }
+    Some([min.x, min.y, max.x, max.y])
+}
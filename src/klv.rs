@@ -0,0 +1,191 @@
+// MISB ST 0601 UAS Datalink Local Set encoding of the pedestal telemetry, carried
+// in H.264 SEI user data unregistered messages.
+
+const UAS_LOCAL_SET_KEY: [u8; 16] = [
+    0x06, 0x0E, 0x2B, 0x34, 0x02, 0x0B, 0x01, 0x01, 0x0E, 0x01, 0x03, 0x01, 0x01, 0x00, 0x00, 0x00,
+];
+
+// Identifies our SEI messages among other user data in the stream.
+pub const SEI_UUID: [u8; 16] = [
+    0x70, 0x65, 0x64, 0x65, 0x2D, 0x6B, 0x6C, 0x76, 0x2D, 0x30, 0x36, 0x30, 0x31, 0x00, 0x00, 0x01,
+];
+
+const TAG_CHECKSUM: u8 = 1;
+const TAG_PRECISION_TIME_STAMP: u8 = 2;
+const TAG_SENSOR_HORIZONTAL_FOV: u8 = 16;
+const TAG_SENSOR_VERTICAL_FOV: u8 = 17;
+const TAG_SENSOR_RELATIVE_AZIMUTH: u8 = 18;
+const TAG_SENSOR_RELATIVE_ELEVATION: u8 = 19;
+const TAG_VERSION: u8 = 65;
+const UAS_LS_VERSION: u8 = 17;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Telemetry {
+    pub timestamp_us: u64,
+    pub azimuth: f32,
+    pub elevation: f32,
+    pub horizontal_fov: f32,
+    pub vertical_fov: f32,
+}
+
+pub fn encode_local_set(telemetry: &Telemetry) -> Vec<u8> {
+    let mut value = Vec::new();
+    push_item(
+        &mut value,
+        TAG_PRECISION_TIME_STAMP,
+        &telemetry.timestamp_us.to_be_bytes(),
+    );
+    push_item(
+        &mut value,
+        TAG_SENSOR_HORIZONTAL_FOV,
+        &map_fov(telemetry.horizontal_fov).to_be_bytes(),
+    );
+    push_item(
+        &mut value,
+        TAG_SENSOR_VERTICAL_FOV,
+        &map_fov(telemetry.vertical_fov).to_be_bytes(),
+    );
+    let azimuth = telemetry.azimuth.rem_euclid(360.0) as f64;
+    let azimuth = (azimuth * (u32::MAX as f64) / 360.0).round() as u32;
+    push_item(
+        &mut value,
+        TAG_SENSOR_RELATIVE_AZIMUTH,
+        &azimuth.to_be_bytes(),
+    );
+    let elevation = telemetry.elevation.clamp(-180.0, 180.0) as f64;
+    let elevation = (elevation * (i32::MAX as f64) / 180.0).round() as i32;
+    push_item(
+        &mut value,
+        TAG_SENSOR_RELATIVE_ELEVATION,
+        &elevation.to_be_bytes(),
+    );
+    push_item(&mut value, TAG_VERSION, &[UAS_LS_VERSION]);
+    // The checksum covers everything up to and including its own tag and length.
+    value.extend_from_slice(&[TAG_CHECKSUM, 2]);
+
+    let mut packet = UAS_LOCAL_SET_KEY.to_vec();
+    push_ber_length(&mut packet, value.len() + 2);
+    packet.extend_from_slice(&value);
+    let checksum = checksum(&packet);
+    packet.extend_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+// Builds an Annex B SEI NAL unit (user data unregistered) carrying the payload.
+pub fn sei_nal(payload: &[u8]) -> Vec<u8> {
+    let mut rbsp = vec![5u8];
+    let mut size = SEI_UUID.len() + payload.len();
+    while size >= 255 {
+        rbsp.push(0xFF);
+        size -= 255;
+    }
+    rbsp.push(size as u8);
+    rbsp.extend_from_slice(&SEI_UUID);
+    rbsp.extend_from_slice(payload);
+    rbsp.push(0x80);
+
+    let mut nal = vec![0, 0, 0, 1, 0x06];
+    let mut zeros = 0;
+    for byte in rbsp {
+        if zeros >= 2 && byte <= 3 {
+            nal.push(3);
+            zeros = 0;
+        }
+        nal.push(byte);
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+    }
+    nal
+}
+
+fn map_fov(fov: f32) -> u16 {
+    (fov.clamp(0.0, 180.0) as f64 * (u16::MAX as f64) / 180.0).round() as u16
+}
+
+fn push_item(buf: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    buf.push(tag);
+    push_ber_length(buf, value.len());
+    buf.extend_from_slice(value);
+}
+
+fn push_ber_length(buf: &mut Vec<u8>, len: usize) {
+    if len < 128 {
+        buf.push(len as u8);
+    } else {
+        let bytes = (len as u32).to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        buf.push(0x80 | (4 - skip) as u8);
+        buf.extend_from_slice(&bytes[skip..]);
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u16 {
+    bytes.iter().enumerate().fold(0u16, |bcc, (i, &b)| {
+        bcc.wrapping_add((b as u16) << (8 * ((i + 1) % 2)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_st0601_example_values() {
+        // Time stamp and field of view values from the ST 0601 item examples.
+        let packet = encode_local_set(&Telemetry {
+            timestamp_us: 1_231_798_102_000_000,
+            azimuth: 90.0,
+            elevation: -90.0,
+            horizontal_fov: 144.5713,
+            vertical_fov: 152.6436,
+        });
+        let mut expected = UAS_LOCAL_SET_KEY.to_vec();
+        expected.extend_from_slice(&[
+            37, // Length
+            0x02, 0x08, 0x00, 0x04, 0x60, 0x50, 0x58, 0x4E, 0x01, 0x80, // Time stamp
+            0x10, 0x02, 0xCD, 0x9C, // Horizontal FOV
+            0x11, 0x02, 0xD9, 0x17, // Vertical FOV
+            0x12, 0x04, 0x40, 0x00, 0x00, 0x00, // Relative azimuth
+            0x13, 0x04, 0xC0, 0x00, 0x00, 0x00, // Relative elevation
+            0x41, 0x01, 0x11, // Version
+            0x01, 0x02, 0x5A, 0x4B, // Checksum
+        ]);
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn checksum_sums_big_endian_words() {
+        assert_eq!(checksum(&[0x06, 0x0E, 0x2B, 0x34]), 0x3142);
+        assert_eq!(checksum(&[0xFF, 0xFF, 0x00, 0x02, 0x01]), 0x0101);
+    }
+
+    #[test]
+    fn ber_length_uses_long_form_from_128() {
+        let mut short = Vec::new();
+        push_ber_length(&mut short, 127);
+        assert_eq!(short, [0x7F]);
+        let mut long = Vec::new();
+        push_ber_length(&mut long, 128);
+        push_ber_length(&mut long, 300);
+        assert_eq!(long, [0x81, 0x80, 0x82, 0x01, 0x2C]);
+    }
+
+    #[test]
+    fn sei_nal_escapes_start_code_emulation() {
+        let nal = sei_nal(&[0x00, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        let mut expected = vec![0, 0, 0, 1, 0x06, 5, 22];
+        // The UUID ends in 00 00 01 as well
+        expected.extend_from_slice(&SEI_UUID[..15]);
+        expected.extend_from_slice(&[0x03, 0x01]);
+        expected.extend_from_slice(&[0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03, 0x00, 0x80]);
+        assert_eq!(nal, expected);
+    }
+
+    #[test]
+    fn sei_nal_splits_large_payload_sizes() {
+        let nal = sei_nal(&[0xAA; 300]);
+        // 16 bytes of UUID and 300 of payload
+        assert_eq!(nal[5..8], [5, 0xFF, 61]);
+        assert_eq!(nal.len(), 5 + 3 + 316 + 1 + 1);
+        assert_eq!(nal.last(), Some(&0x80));
+    }
+}
@@ -0,0 +1,426 @@
+use crate::config;
+use crate::radar_cam;
+use crate::readback;
+use crate::scenario;
+use crate::stream;
+use bevy::asset::RenderAssetUsages;
+use bevy::camera::visibility::RenderLayers;
+use bevy::core_pipeline::tonemapping::{DebandDither, Tonemapping};
+use bevy::light::NotShadowCaster;
+use bevy::prelude::*;
+use bevy::render::render_resource::{
+    AsBindGroup, Extent3d, TextureDimension, TextureFormat, TextureUsages,
+};
+use bevy::render::view::Hdr;
+use bevy::shader::ShaderRef;
+use image::ExtendedColorType;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+const DEPTH_SHADER_ASSET_PATH: &str = "shaders/depth.wgsl";
+const DEPTH_LAYER: usize = 2;
+const SEGMENTATION_LAYER: usize = 3;
+// Depth is written in millimeters.
+const DEPTH_UNITS_PER_METER: f32 = 1000.0;
+
+#[derive(Component)]
+pub struct LabelCamera;
+
+#[derive(Asset, TypePath, AsBindGroup, Debug, Clone)]
+pub struct DepthMaterial {
+    #[uniform(0)]
+    pub units_per_meter: f32,
+}
+
+impl Material for DepthMaterial {
+    fn fragment_shader() -> ShaderRef {
+        DEPTH_SHADER_ASSET_PATH.into()
+    }
+}
+
+// Class ids fill the red channel of the segmentation and instance ids green and blue.
+pub const MAX_CLASSES: usize = u8::MAX as usize;
+pub const MAX_INSTANCES: usize = u16::MAX as usize;
+
+// A label camera and the image it renders to.
+struct LabelTarget {
+    camera: Entity,
+    image: Handle<Image>,
+}
+
+// Pixels of a label image read back from the GPU, tightly packed RGBA.
+struct LabelImage {
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+#[derive(Resource)]
+pub struct Labels {
+    directory: PathBuf,
+    every_n_frames: u64,
+    width: u32,
+    height: u32,
+    depth: Option<LabelTarget>,
+    segmentation: Option<LabelTarget>,
+    depth_material: Option<Handle<DepthMaterial>>,
+    // Class ids start at 1, 0 is the background.
+    classes: BTreeMap<String, u8>,
+    // Instance ids start at 1 and are given out in the order targets are first seen.
+    instances: HashMap<Entity, u16>,
+    segmentation_materials: HashMap<Entity, Handle<StandardMaterial>>,
+    // Everything that is not a target, still drawn so it hides the targets behind it
+    background_material: Handle<StandardMaterial>,
+    tx: Sender<LabelImage>,
+}
+
+#[derive(Serialize)]
+struct LabelIndex<'a> {
+    classes: &'a BTreeMap<String, u8>,
+    instances: BTreeMap<u16, InstanceLabel<'a>>,
+}
+
+#[derive(Serialize)]
+struct InstanceLabel<'a> {
+    name: &'a str,
+    class: &'a str,
+}
+
+impl Labels {
+    // Segmentation pixels hold the class id in red and the instance id in green and blue.
+    fn segmentation_color(class: u8, instance: u16) -> Color {
+        let [high, low] = instance.to_be_bytes();
+        Color::linear_rgb(
+            class as f32 / 255.0,
+            high as f32 / 255.0,
+            low as f32 / 255.0,
+        )
+    }
+
+    fn write_index(&self, targets: &Query<&scenario::Target>) {
+        let instances = self
+            .instances
+            .iter()
+            .filter_map(|(entity, id)| {
+                let target = targets.get(*entity).ok()?;
+                Some((
+                    *id,
+                    InstanceLabel {
+                        name: &target.name,
+                        class: &target.class,
+                    },
+                ))
+            })
+            .collect();
+        let index = LabelIndex {
+            classes: &self.classes,
+            instances,
+        };
+        let path = self.directory.join("labels.json");
+        match serde_json::to_string_pretty(&index) {
+            Ok(json) => {
+                if let Err(e) = fs::write(&path, json) {
+                    eprintln!("Failed to write {}: {}", path.display(), e);
+                }
+            }
+            Err(e) => eprintln!("Failed to serialize labels: {}", e),
+        }
+    }
+}
+
+// Linear 8 bit target so the encoded depth and ids come out exactly as written.
+fn label_target(images: &mut Assets<Image>, width: u32, height: u32) -> Handle<Image> {
+    let size = Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+    };
+    let mut image = Image::new_fill(
+        size,
+        TextureDimension::D2,
+        &[0, 0, 0, 0],
+        TextureFormat::Rgba8Unorm,
+        RenderAssetUsages::default(),
+    );
+    image.texture_descriptor.usage = TextureUsages::TEXTURE_BINDING
+        | TextureUsages::COPY_SRC
+        | TextureUsages::COPY_DST
+        | TextureUsages::RENDER_ATTACHMENT;
+    images.add(image)
+}
+
+// Spawns a camera on the same spot as the radar camera that only sees one label layer.
+// It renders the ideal pinhole image, without the lens distortion of the stream.
+fn spawn_label_cam(
+    commands: &mut Commands,
+    images: &mut Assets<Image>,
+    radar_camera: Entity,
+    width: u32,
+    height: u32,
+    layer: usize,
+    clear_color: Color,
+) -> LabelTarget {
+    let image_handle = label_target(images, width, height);
+    let camera = commands
+        .spawn((
+            Transform::IDENTITY,
+            Camera3d::default(),
+            Camera {
+                target: image_handle.clone().into(),
+                order: 1,
+                clear_color: ClearColorConfig::Custom(clear_color),
+                ..default()
+            },
+            // The HDR intermediate keeps 8 bit values intact, the default sRGB one rounds them.
+            Hdr,
+            Tonemapping::None,
+            DebandDither::Disabled,
+            Msaa::Off,
+            RenderLayers::layer(layer),
+            LabelCamera,
+        ))
+        .id();
+    commands.entity(radar_camera).add_child(camera);
+    LabelTarget {
+        camera,
+        image: image_handle,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn start_labels(
+    commands: &mut Commands,
+    images: &mut Assets<Image>,
+    materials: &mut Assets<StandardMaterial>,
+    depth_materials: &mut Assets<DepthMaterial>,
+    radar_camera: Entity,
+    width: u32,
+    height: u32,
+    config: &config::LabelsConfig,
+) {
+    let directory = PathBuf::from(&config.directory);
+    if let Err(e) = fs::create_dir_all(&directory) {
+        eprintln!(
+            "Failed to create label directory {}: {}",
+            config.directory, e
+        );
+        return;
+    }
+    // Nothing in view reads as the farthest depth.
+    let depth = config.depth.then(|| {
+        spawn_label_cam(
+            commands,
+            images,
+            radar_camera,
+            width,
+            height,
+            DEPTH_LAYER,
+            Color::WHITE,
+        )
+    });
+    let segmentation = config.segmentation.then(|| {
+        spawn_label_cam(
+            commands,
+            images,
+            radar_camera,
+            width,
+            height,
+            SEGMENTATION_LAYER,
+            Color::BLACK,
+        )
+    });
+    let depth_material = config.depth.then(|| {
+        depth_materials.add(DepthMaterial {
+            units_per_meter: DEPTH_UNITS_PER_METER,
+        })
+    });
+
+    let (tx, rx) = mpsc::channel::<LabelImage>();
+    thread::spawn(move || {
+        run_writer(rx);
+    });
+    println!("Writing labels to {}", config.directory);
+
+    commands.insert_resource(Labels {
+        directory,
+        every_n_frames: config.every_n_frames.max(1) as u64,
+        width,
+        height,
+        depth,
+        segmentation,
+        depth_material,
+        classes: BTreeMap::new(),
+        instances: HashMap::new(),
+        segmentation_materials: HashMap::new(),
+        background_material: materials.add(StandardMaterial {
+            base_color: Color::BLACK,
+            unlit: true,
+            ..default()
+        }),
+        tx,
+    });
+}
+
+// Encodes the PNG files away from the main thread.
+fn run_writer(rx: Receiver<LabelImage>) {
+    while let Ok(label) = rx.recv() {
+        let rgb: Vec<u8> = label
+            .pixels
+            .chunks_exact(4)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .collect();
+        if let Err(e) = image::save_buffer(
+            &label.path,
+            &rgb,
+            label.width,
+            label.height,
+            ExtendedColorType::Rgb8,
+        ) {
+            eprintln!("Failed to write {}: {}", label.path.display(), e);
+        }
+    }
+}
+
+// Gives every new mesh child copies on the label layers, one writing its depth and one
+// painted with the class and instance of the target it belongs to, or black.
+#[allow(clippy::type_complexity)]
+pub fn spawn_label_twins(
+    mut commands: Commands,
+    mut labels: ResMut<Labels>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    meshes: Query<(Entity, &Mesh3d), (Added<Mesh3d>, Without<radar_cam::Twin>)>,
+    parents: Query<&ChildOf>,
+    targets: Query<&scenario::Target>,
+) {
+    let mut new_instances = false;
+    for (entity, mesh) in meshes.iter() {
+        if let Some(material) = labels.depth_material.clone() {
+            commands.entity(entity).with_child((
+                Mesh3d(mesh.0.clone()),
+                MeshMaterial3d(material),
+                Transform::IDENTITY,
+                RenderLayers::layer(DEPTH_LAYER),
+                NotShadowCaster,
+                radar_cam::Twin,
+            ));
+        }
+        if labels.segmentation.is_none() {
+            continue;
+        }
+        let target = std::iter::once(entity)
+            .chain(parents.iter_ancestors(entity))
+            .find_map(|e| targets.get(e).ok().map(|t| (e, t)));
+        let material = match target {
+            // Meshes that are not part of a target are background.
+            None => labels.background_material.clone(),
+            Some((target_entity, target)) => {
+                match labels.segmentation_materials.get(&target_entity) {
+                    Some(material) => material.clone(),
+                    None => {
+                        let next_class = labels.classes.len() as u8 + 1;
+                        let class = *labels
+                            .classes
+                            .entry(target.class.clone())
+                            .or_insert(next_class);
+                        let instance = labels.instances.len() as u16 + 1;
+                        labels.instances.insert(target_entity, instance);
+                        let material = materials.add(StandardMaterial {
+                            base_color: Labels::segmentation_color(class, instance),
+                            unlit: true,
+                            ..default()
+                        });
+                        labels
+                            .segmentation_materials
+                            .insert(target_entity, material.clone());
+                        new_instances = true;
+                        material
+                    }
+                }
+            }
+        };
+        commands.entity(entity).with_child((
+            Mesh3d(mesh.0.clone()),
+            MeshMaterial3d(material),
+            Transform::IDENTITY,
+            RenderLayers::layer(SEGMENTATION_LAYER),
+            NotShadowCaster,
+            radar_cam::Twin,
+        ));
+    }
+    if new_instances {
+        labels.write_index(&targets);
+    }
+}
+
+// The label cameras follow the radar camera zoom.
+pub fn sync_label_cams(
+    radar_query: Query<&Projection, (With<radar_cam::RadarCamera>, Without<LabelCamera>)>,
+    mut label_query: Query<&mut Projection, With<LabelCamera>>,
+) {
+    let Ok(radar_projection) = radar_query.single() else {
+        return;
+    };
+    for mut projection in label_query.iter_mut() {
+        *projection = radar_projection.clone();
+    }
+}
+
+// Reads back the label images on the same tick as the streamed radar camera frame and
+// names them after its frame number.
+pub fn capture_labels(
+    labels: Res<Labels>,
+    mut readbacks: ResMut<readback::ReadbackQueue>,
+    stream_query: Query<&stream::CameraStream>,
+    camera_query: Query<Entity, With<radar_cam::RadarCamera>>,
+    // Radar camera frame the labels were last captured for
+    mut captured: Local<u64>,
+) {
+    let Ok(camera) = camera_query.single() else {
+        return;
+    };
+    let Some(stream) = stream_query.iter().find(|s| s.camera == camera) else {
+        return;
+    };
+    if stream.frame_number == *captured || stream.frame_number % labels.every_n_frames != 0 {
+        return;
+    }
+    *captured = stream.frame_number;
+    let outputs = [
+        ("depth", &labels.depth),
+        ("segmentation", &labels.segmentation),
+    ];
+    let (width, height) = (labels.width, labels.height);
+    let size = width as usize * height as usize * 4;
+    for (kind, target) in outputs {
+        let Some(target) = target else {
+            continue;
+        };
+        let path = labels
+            .directory
+            .join(format!("{}_{:08}.png", kind, stream.frame_number));
+        let tx = labels.tx.clone();
+        readbacks.request(readback::ReadbackRequest {
+            target: target.camera,
+            image: target.image.clone(),
+            on_complete: Box::new(move |pixels| {
+                if pixels.len() != size {
+                    eprintln!("Failed to read back {}.", path.display());
+                    return;
+                }
+                let _ = tx.send(LabelImage {
+                    path,
+                    width,
+                    height,
+                    pixels,
+                });
+            }),
+            // Never skip a label frame, the render thread waits for a staging buffer instead.
+            wait: true,
+        });
+    }
+}
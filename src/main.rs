@@ -1,18 +1,54 @@
 use bevy::prelude::*;
+use bevy::sprite_render::Material2dPlugin;
+use bevy::time::TimeSystems;
 use bevy::window::WindowMode;
 use bevy_egui::{EguiPlugin, EguiPrimaryContextPass};
 use bevy_panorbit_camera::PanOrbitCameraPlugin;
+use std::time::Duration;
 
 mod config;
 mod env;
+mod ground_truth;
+mod klv;
+mod labels;
+mod metrics;
+mod mjpeg;
+#[cfg(feature = "native-encoder")]
+mod native_stream;
+mod overlay;
+mod post_process;
 mod radar;
 mod radar_cam;
+mod readback;
+mod recording;
+#[cfg(feature = "rtsp-server")]
+mod rtsp_server;
+mod scenario;
+mod scene;
+mod simulation;
+mod snapshot;
 mod stream;
+mod supervisor;
+mod terrain;
+mod thermal;
 mod ui;
 
 fn main() {
     let config = config::Config::from_file("config.toml")
         .expect("Failed to load configuration from config.toml");
+    let zoom = radar_cam::Zoom::new(&config);
+    let recorder = recording::Recorder::new(&config);
+    // A deterministic tick is one frame of the fastest camera.
+    let frame_rate = config
+        .cameras
+        .iter()
+        .map(|c| c.frame_rate)
+        .fold(config.stream.frame_rate, u32::max);
+    let simulation = simulation::Simulation::new(&config.simulation);
+    let time_update = simulation::fixed_step(
+        &config.simulation,
+        Duration::from_secs_f64(1.0 / frame_rate as f64),
+    );
     let frame_buffer = stream::FrameBuffer::new(
         config.radar_cam_render_width,
         config.radar_cam_render_height,
@@ -29,48 +65,213 @@ fn main() {
         }))
         .add_plugins(EguiPlugin::default())
         .add_plugins(PanOrbitCameraPlugin)
+        .add_plugins(Material2dPlugin::<post_process::PostProcessMaterial>::default())
+        .add_plugins(MaterialPlugin::<labels::DepthMaterial>::default())
+        .add_plugins(readback::ReadbackPlugin)
         .insert_resource(config)
+        .insert_resource(zoom)
+        .insert_resource(recorder)
         .insert_resource(frame_buffer)
-        .insert_resource(Time::<Fixed>::from_seconds(1.0 / 25.0))
+        .insert_resource(simulation)
+        .insert_resource(time_update)
         .insert_resource(radar::Radar::default())
-        .add_systems(Startup, setup)
+        .add_systems(Startup, (setup, overlay::spawn_overlay).chain())
+        .add_systems(First, simulation::advance.before(TimeSystems))
         .add_systems(EguiPrimaryContextPass, ui::ui_system)
-        .add_systems(FixedUpdate, stream::stream_frames)
+        // Frames are grabbed once everything has moved, on the real clock so streams keep
+        // their frame rate whatever the simulation speed.
+        .add_systems(
+            PostUpdate,
+            stream::stream_frames.after(TransformSystems::Propagate),
+        )
+        .add_systems(
+            PostUpdate,
+            ground_truth::publish_ground_truth
+                .after(stream::stream_frames)
+                .run_if(resource_exists::<ground_truth::GroundTruthPublisher>),
+        )
+        .add_systems(
+            PostUpdate,
+            labels::capture_labels
+                .after(stream::stream_frames)
+                .run_if(resource_exists::<labels::Labels>),
+        )
+        .add_systems(
+            PostUpdate,
+            recording::record_frames.after(stream::stream_frames),
+        )
+        .add_systems(
+            Last,
+            (recording::stop_on_exit, supervisor::shutdown_on_exit).chain(),
+        )
         .add_systems(Update, radar::handle_commands)
+        .add_systems(Update, metrics::update_metrics)
         .add_systems(Update, radar::update_radar)
+        .add_systems(Update, radar_cam::update_zoom)
+        .add_systems(
+            Update,
+            post_process::update_post_process.after(radar_cam::update_zoom),
+        )
+        .add_systems(Update, scenario::update_targets)
+        .add_systems(Update, overlay::update_overlay)
+        .add_systems(
+            Update,
+            (thermal::spawn_thermal_twins, thermal::update_palette)
+                .run_if(resource_exists::<thermal::Thermal>),
+        )
+        .add_systems(
+            Update,
+            (
+                labels::spawn_label_twins,
+                labels::sync_label_cams.after(radar_cam::update_zoom),
+            )
+                .run_if(resource_exists::<labels::Labels>),
+        )
         .run();
 }
 
+#[allow(clippy::too_many_arguments)]
 fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    images: ResMut<Assets<Image>>,
+    mut post_process_materials: ResMut<Assets<post_process::PostProcessMaterial>>,
+    mut depth_materials: ResMut<Assets<labels::DepthMaterial>>,
+    mut images: ResMut<Assets<Image>>,
     asset_server: Res<AssetServer>,
     frame_buffer: Res<stream::FrameBuffer>,
+    zoom: Res<radar_cam::Zoom>,
     config: Res<config::Config>,
 ) {
     env::spawn_env(
         &mut commands,
         &mut meshes,
         &mut materials,
-        asset_server,
+        &asset_server,
         &config,
     );
+    if let Some(scenario_file) = &config.scenario_file {
+        match scenario::Scenario::from_file(scenario_file) {
+            Ok(scenario) => scenario::spawn_scenario(
+                &mut commands,
+                &mut meshes,
+                &mut materials,
+                &asset_server,
+                &scenario,
+            ),
+            Err(e) => eprintln!("Failed to load scenario {}: {}", scenario_file, e),
+        }
+    }
     let pivot = radar::spawn_radar(&mut meshes, &mut materials, &mut commands, &config);
-    let image = radar_cam::spawn_radar_cam(
-        meshes,
+    let (image, camera) = radar_cam::spawn_radar_cam(
+        &mut meshes,
         &mut materials,
         &mut commands,
-        images,
+        &mut images,
         pivot,
-        frame_buffer,
+        &frame_buffer,
+        &zoom,
         &config,
     );
+    if let Some(labels) = &config.labels {
+        labels::start_labels(
+            &mut commands,
+            &mut images,
+            &mut materials,
+            &mut depth_materials,
+            camera,
+            config.radar_cam_render_width,
+            config.radar_cam_render_height,
+            labels,
+        );
+    }
+    let lens = post_process::LensModel::new(
+        config.lens.as_ref(),
+        config.radar_cam_render_width,
+        config.radar_cam_render_height,
+    );
+    let sensor = post_process::SensorModel::new(config.sensor.as_ref(), config.stream.frame_rate);
+    let image = if config.lens.is_some() || config.sensor.is_some() {
+        post_process::spawn_post_process(
+            &mut commands,
+            &mut meshes,
+            &mut images,
+            &mut post_process_materials,
+            image,
+            &lens,
+            &sensor,
+            &zoom,
+        )
+    } else {
+        image
+    };
+    commands.insert_resource(lens);
+    commands.insert_resource(sensor);
+    let stream_config = &config.stream;
+    let path = stream_config.path();
+    stream::start_servers(stream_config);
     stream::start_stream(
         &mut commands,
+        camera,
         image,
-        config.radar_cam_render_width,
-        config.radar_cam_render_height,
+        frame_buffer.clone(),
+        path,
+        stream_config.frame_rate,
+        &config,
     );
+    if stream_config.enabled {
+        println!(
+            "Radar camera streaming at {}",
+            stream::stream_url(stream_config, path)
+        );
+    }
+    let mut mjpeg_streams = vec![(path.to_string(), frame_buffer.clone())];
+    for camera_config in &config.cameras {
+        let (image, camera) = match camera_config.kind {
+            config::CameraKind::Visible => {
+                radar_cam::spawn_sensor_cam(&mut commands, &mut images, pivot, camera_config)
+            }
+            config::CameraKind::Thermal => {
+                thermal::spawn_thermal_cam(&mut commands, &mut images, pivot, camera_config)
+            }
+        };
+        let camera_buffer = stream::FrameBuffer::new(camera_config.width, camera_config.height);
+        mjpeg_streams.push((camera_config.stream_path.clone(), camera_buffer.clone()));
+        stream::start_stream(
+            &mut commands,
+            camera,
+            image,
+            camera_buffer,
+            &camera_config.stream_path,
+            camera_config.frame_rate,
+            &config,
+        );
+        if stream_config.enabled {
+            println!(
+                "Camera {} streaming at {}",
+                camera_config.name,
+                stream::stream_url(stream_config, &camera_config.stream_path)
+            );
+        }
+    }
+    if let Some(mjpeg) = &config.mjpeg {
+        mjpeg::start_server(mjpeg, mjpeg_streams);
+    }
+    if let Some(port) = config.snapshot.port {
+        snapshot::start_server(&config.snapshot, port, frame_buffer.clone());
+    }
+    if let Some(metrics) = &config.metrics {
+        metrics::start_server(metrics);
+    }
+    let has_thermal = config
+        .cameras
+        .iter()
+        .any(|c| c.kind == config::CameraKind::Thermal);
+    if has_thermal {
+        let thermal = config.thermal.clone().unwrap_or_default();
+        commands.insert_resource(thermal::Thermal::new(thermal));
+    }
+    if let Some(ground_truth) = &config.ground_truth {
+        ground_truth::start_publisher(&mut commands, ground_truth);
+    }
 }
@@ -0,0 +1,305 @@
+use crate::config;
+use crate::stream;
+use crate::supervisor;
+use bevy::prelude::*;
+use once_cell::sync::Lazy;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Counters and gauges collected across the app, read by the UI and the HTTP endpoint.
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+type ClientCount = Box<dyn Fn() -> usize + Send + Sync>;
+
+#[derive(Default)]
+struct Metrics {
+    frames_rendered: AtomicU64,
+    frame_time_us: AtomicU64,
+    commands: AtomicU64,
+    command_latency_us: AtomicU64,
+    streams: Mutex<Vec<StreamMetrics>>,
+    // Connected clients by kind, counted when read
+    clients: Mutex<Vec<(&'static str, ClientCount)>>,
+}
+
+struct StreamMetrics {
+    path: String,
+    stats: Arc<stream::FrameStats>,
+    // Bytes per second written to the encoder, sampled once a second
+    throughput: f64,
+    sampled_at: Instant,
+    sampled_bytes: u64,
+}
+
+pub fn register_stream(path: &str, stats: Arc<stream::FrameStats>) {
+    METRICS.streams.lock().unwrap().push(StreamMetrics {
+        path: path.to_string(),
+        stats,
+        throughput: 0.0,
+        sampled_at: Instant::now(),
+        sampled_bytes: 0,
+    });
+}
+
+// Registers a kind of client, such as MJPEG viewers, with a function counting them.
+pub fn register_clients(kind: &'static str, count: impl Fn() -> usize + Send + Sync + 'static) {
+    METRICS
+        .clients
+        .lock()
+        .unwrap()
+        .push((kind, Box::new(count)));
+}
+
+// Time from a protocol command arriving to its reply.
+pub fn record_command(latency: Duration) {
+    METRICS.commands.fetch_add(1, Ordering::Relaxed);
+    METRICS
+        .command_latency_us
+        .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+}
+
+pub fn update_metrics(time: Res<Time>) {
+    METRICS.frames_rendered.fetch_add(1, Ordering::Relaxed);
+    METRICS
+        .frame_time_us
+        .store(time.delta().as_micros() as u64, Ordering::Relaxed);
+    for stream in METRICS.streams.lock().unwrap().iter_mut() {
+        let elapsed = stream.sampled_at.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            let bytes = stream.stats.bytes_written.load(Ordering::Relaxed);
+            stream.throughput = (bytes - stream.sampled_bytes) as f64 / elapsed.as_secs_f64();
+            stream.sampled_at = Instant::now();
+            stream.sampled_bytes = bytes;
+        }
+    }
+}
+
+// Lines for the metrics panel.
+pub fn summary() -> Vec<String> {
+    let mut lines = Vec::new();
+    let frame_time = METRICS.frame_time_us.load(Ordering::Relaxed) as f32 / 1000.0;
+    lines.push(format!(
+        "Rendered frames: {}  Frame time: {:.1} ms",
+        METRICS.frames_rendered.load(Ordering::Relaxed),
+        frame_time
+    ));
+    for stream in METRICS.streams.lock().unwrap().iter() {
+        lines.push(format!(
+            "Stream {}: {} throughput {:.1} MB/s",
+            stream.path,
+            stream.stats.summary(),
+            stream.throughput / 1e6
+        ));
+    }
+    let commands = METRICS.commands.load(Ordering::Relaxed);
+    let latency = METRICS.command_latency_us.load(Ordering::Relaxed);
+    lines.push(format!(
+        "Commands: {}  Mean latency: {:.1} ms",
+        commands,
+        latency as f32 / commands.max(1) as f32 / 1000.0
+    ));
+    let clients: Vec<String> = METRICS
+        .clients
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(kind, count)| format!("{} {}", kind, count()))
+        .collect();
+    if !clients.is_empty() {
+        lines.push(format!("Clients: {}", clients.join(", ")));
+    }
+    lines.extend(supervisor::health());
+    lines
+}
+
+// All metrics in the Prometheus text exposition format.
+fn render() -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, f64)>| {
+        let _ = writeln!(out, "# HELP pede_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE pede_{} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "pede_{}{} {}", name, labels, value);
+        }
+    };
+    let value = |counter: &AtomicU64| vec![(String::new(), counter.load(Ordering::Relaxed) as f64)];
+
+    metric(
+        "frames_rendered_total",
+        "counter",
+        "Frames rendered by the app.",
+        value(&METRICS.frames_rendered),
+    );
+    metric(
+        "frame_time_seconds",
+        "gauge",
+        "Duration of the last rendered frame.",
+        vec![(
+            String::new(),
+            METRICS.frame_time_us.load(Ordering::Relaxed) as f64 / 1e6,
+        )],
+    );
+
+    let streams = METRICS.streams.lock().unwrap();
+    let per_stream = |get: &dyn Fn(&StreamMetrics) -> f64| -> Vec<(String, f64)> {
+        streams
+            .iter()
+            .map(|s| (format!("{{stream=\"{}\"}}", escape_label(&s.path)), get(s)))
+            .collect()
+    };
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed) as f64;
+    metric(
+        "stream_frames_captured_total",
+        "counter",
+        "Frames read back from the GPU.",
+        per_stream(&|s| load(&s.stats.captured)),
+    );
+    metric(
+        "stream_frames_encoded_total",
+        "counter",
+        "Frames handed to the encoder.",
+        per_stream(&|s| load(&s.stats.written)),
+    );
+    metric(
+        "stream_frames_dropped_total",
+        "counter",
+        "Frames lost before or at the encoder.",
+        per_stream(&|s| load(&s.stats.dropped)),
+    );
+    metric(
+        "stream_frames_duplicated_total",
+        "counter",
+        "Copies of the previous frame written in place of dropped ones.",
+        per_stream(&|s| load(&s.stats.duplicated)),
+    );
+    metric(
+        "stream_encoder_latency_seconds",
+        "gauge",
+        "Time from capture until the last frame was handed to the encoder.",
+        per_stream(&|s| load(&s.stats.latency_us) / 1e6),
+    );
+    metric(
+        "stream_written_bytes_total",
+        "counter",
+        "Bytes written to the encoder.",
+        per_stream(&|s| load(&s.stats.bytes_written)),
+    );
+    metric(
+        "stream_write_throughput_bytes",
+        "gauge",
+        "Bytes per second written to the encoder over the last second.",
+        per_stream(&|s| s.throughput),
+    );
+    drop(streams);
+
+    metric(
+        "commands_total",
+        "counter",
+        "Protocol commands answered.",
+        value(&METRICS.commands),
+    );
+    metric(
+        "command_latency_seconds_total",
+        "counter",
+        "Summed time from protocol commands arriving to their replies.",
+        vec![(String::new(), load(&METRICS.command_latency_us) / 1e6)],
+    );
+    let clients = METRICS
+        .clients
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(kind, count)| (format!("{{kind=\"{}\"}}", kind), count() as f64))
+        .collect();
+    metric("clients", "gauge", "Connected clients.", clients);
+
+    let processes = supervisor::processes();
+    metric(
+        "process_up",
+        "gauge",
+        "Whether a supervised process is running.",
+        processes
+            .iter()
+            .map(|p| {
+                (
+                    format!("{{process=\"{}\"}}", escape_label(p.name())),
+                    p.is_running() as u8 as f64,
+                )
+            })
+            .collect(),
+    );
+    metric(
+        "process_restarts_total",
+        "counter",
+        "Restarts of a supervised process.",
+        processes
+            .iter()
+            .map(|p| {
+                (
+                    format!("{{process=\"{}\"}}", escape_label(p.name())),
+                    p.restarts() as f64,
+                )
+            })
+            .collect(),
+    );
+    out
+}
+
+// Escapes a label value as the Prometheus text format expects.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Serves the metrics at http://127.0.0.1:<port>/metrics.
+pub fn start_server(config: &config::MetricsConfig) {
+    let address = format!("127.0.0.1:{}", config.port);
+    thread::spawn(move || {
+        let listener = TcpListener::bind(&address).expect("Could not bind metrics address");
+        println!("Metrics available on http://{}/metrics", address);
+        for stream in listener.incoming() {
+            match stream {
+                // A slow scraper must not hold up the others
+                Ok(stream) => {
+                    thread::spawn(move || {
+                        if let Err(e) = respond(stream) {
+                            eprintln!("Failed to answer a metrics request: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept a metrics connection: {:?}", e),
+            }
+        }
+    });
+}
+
+fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+    if path != "/metrics" && path != "/" {
+        return stream.write_all(
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+    }
+    let body = render();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
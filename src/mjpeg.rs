@@ -0,0 +1,181 @@
+use crate::config;
+use crate::metrics;
+use crate::stream;
+use image::codecs::jpeg::JpegEncoder;
+use image::ExtendedColorType;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Clients across all feeds, read by the metrics without locking the feeds.
+static CONNECTED_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+const BOUNDARY: &str = "frame";
+// Frames queued for a client, a slower one skips frames.
+const CLIENT_QUEUE: usize = 2;
+// A client that takes longer than this to accept a frame is disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Clients watching one stream path, each fed by its own sender thread so a slow one holds
+// up nobody else.
+#[derive(Default)]
+struct Feed {
+    clients: Mutex<Vec<SyncSender<Arc<Vec<u8>>>>>,
+}
+
+// Serves each stream's frame buffer as MJPEG at http://127.0.0.1:<port>/<path>, with an
+// index of all streams at the root.
+pub fn start_server(config: &config::MjpegConfig, streams: Vec<(String, stream::FrameBuffer)>) {
+    let paths: Vec<String> = streams.iter().map(|(path, _)| path.clone()).collect();
+    let mut feeds = HashMap::new();
+    for (path, frame_buffer) in streams {
+        let feed = Arc::new(Feed::default());
+        feeds.insert(path, feed.clone());
+        let quality = config.quality.clamp(1, 100);
+        let interval = Duration::from_secs_f32(1.0 / config.frame_rate.max(1) as f32);
+        thread::spawn(move || {
+            run_encoder(feed, frame_buffer, quality, interval);
+        });
+    }
+
+    metrics::register_clients("mjpeg", || CONNECTED_CLIENTS.load(Ordering::Relaxed));
+
+    let feeds = Arc::new(feeds);
+    let paths = Arc::new(paths);
+    let address = format!("127.0.0.1:{}", config.port);
+    thread::spawn(move || {
+        let listener = TcpListener::bind(&address).expect("Could not bind MJPEG address");
+        println!("MJPEG server running on http://{}", address);
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let feeds = feeds.clone();
+                    let paths = paths.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = accept_client(stream, &feeds, &paths) {
+                            eprintln!("Failed to accept an MJPEG client: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept an MJPEG connection: {:?}", e),
+            }
+        }
+    });
+}
+
+fn accept_client(
+    mut stream: TcpStream,
+    feeds: &HashMap<String, Arc<Feed>>,
+    paths: &[String],
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let path = request
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("/")
+        .trim_start_matches('/');
+
+    if path.is_empty() {
+        let images: String = paths
+            .iter()
+            .map(|p| {
+                format!(
+                    "<h3>{}</h3><img src=\"/{}\" style=\"max-width:100%\">",
+                    p, p
+                )
+            })
+            .collect();
+        let body = format!("<html><body>{}</body></html>", images);
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        return Ok(());
+    }
+    let Some(feed) = feeds.get(path) else {
+        stream.write_all(
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        )?;
+        return Ok(());
+    };
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        BOUNDARY
+    )?;
+    println!("MJPEG client {} connected to {}", stream.peer_addr()?, path);
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let (tx, rx) = mpsc::sync_channel(CLIENT_QUEUE);
+    thread::spawn(move || send_to_client(stream, rx));
+    feed.clients.lock().unwrap().push(tx);
+    CONNECTED_CLIENTS.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+// Writes the queued parts until the client goes away.
+fn send_to_client(mut stream: TcpStream, rx: Receiver<Arc<Vec<u8>>>) {
+    while let Ok(part) = rx.recv() {
+        if let Err(e) = stream.write_all(&part) {
+            println!("MJPEG client disconnected: {}", e);
+            return;
+        }
+    }
+}
+
+// Encodes the latest frame at the configured rate while anyone is watching.
+fn run_encoder(
+    feed: Arc<Feed>,
+    frame_buffer: stream::FrameBuffer,
+    quality: u8,
+    interval: Duration,
+) {
+    let mut jpeg = Vec::new();
+    loop {
+        let started = Instant::now();
+        if !feed.clients.lock().unwrap().is_empty() {
+            jpeg.clear();
+            let rgb = frame_buffer.rgb();
+            let encoded = JpegEncoder::new_with_quality(&mut jpeg, quality).encode(
+                &rgb,
+                frame_buffer.width,
+                frame_buffer.height,
+                ExtendedColorType::Rgb8,
+            );
+            match encoded {
+                Ok(()) => {
+                    let header = format!(
+                        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                        BOUNDARY,
+                        jpeg.len()
+                    );
+                    let part = Arc::new([header.as_bytes(), &jpeg, b"\r\n"].concat());
+                    let mut clients = feed.clients.lock().unwrap();
+                    clients.retain(|client| match client.try_send(part.clone()) {
+                        Ok(()) | Err(TrySendError::Full(_)) => true,
+                        Err(TrySendError::Disconnected(_)) => {
+                            CONNECTED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+                            false
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to encode MJPEG frame: {}", e),
+            }
+        }
+        thread::sleep(interval.saturating_sub(started.elapsed()));
+    }
+}
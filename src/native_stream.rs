@@ -0,0 +1,237 @@
+use crate::config;
+use crate::klv;
+use crate::metrics;
+use once_cell::sync::Lazy;
+use openh264::encoder::{BitRate, Encoder, EncoderConfig, FrameRate, FrameType, IntraFramePeriod};
+use openh264::formats::{BgraSliceU8, YUVBuffer};
+use openh264::OpenH264API;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Streams by path, filled in by start_stream and looked up by the HTTP server.
+static STREAMS: Lazy<Mutex<HashMap<String, Arc<Viewers>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// Viewers across all streams, read by the metrics without locking the streams.
+static CONNECTED_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+// Access units queued for a viewer before it is considered too slow and skips ahead.
+const VIEWER_QUEUE: usize = 8;
+// A viewer that takes longer than this to accept an access unit is disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Viewers {
+    clients: Mutex<Vec<Viewer>>,
+    // Set when a client connects so it does not wait a whole GOP for a keyframe.
+    keyframe_requested: AtomicBool,
+}
+
+// A connected client, fed by its own sender thread so a slow one holds up nobody else.
+struct Viewer {
+    tx: SyncSender<Arc<Vec<u8>>>,
+    // Viewers start at a keyframe so their decoder has the parameter sets, and start over
+    // at the next one when their queue was full.
+    waiting: bool,
+}
+
+// The encoder end of a stream, frames are dropped while the encoder is busy.
+pub struct NativeStream {
+    frame_tx: SyncSender<Vec<u8>>,
+}
+
+impl NativeStream {
+    // False when the encoder is still busy with the previous frame or has stopped.
+    pub fn write_frame(&self, frame: &[u8]) -> bool {
+        match self.frame_tx.try_send(frame.to_vec()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => false,
+            Err(TrySendError::Disconnected(_)) => {
+                eprintln!("Native encoder has stopped");
+                false
+            }
+        }
+    }
+}
+
+pub fn url(port: u16, path: &str) -> String {
+    format!("http://127.0.0.1:{}/{}", port, path)
+}
+
+// Serves every stream as a raw H.264 elementary stream at http://127.0.0.1:<port>/<path>.
+pub fn start_server(port: u16) {
+    metrics::register_clients("h264_http", || CONNECTED_CLIENTS.load(Ordering::Relaxed));
+    let address = format!("127.0.0.1:{}", port);
+    thread::spawn(move || {
+        let listener = TcpListener::bind(&address).expect("Could not bind stream address");
+        println!("Native stream server running on {}", address);
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    thread::spawn(move || {
+                        if let Err(e) = accept_viewer(stream) {
+                            eprintln!("Failed to accept a stream viewer: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept a stream connection: {:?}", e),
+            }
+        }
+    });
+}
+
+fn accept_viewer(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let path = request
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("/")
+        .trim_start_matches('/')
+        .to_string();
+
+    let viewers = STREAMS.lock().unwrap().get(&path).cloned();
+    let Some(viewers) = viewers else {
+        stream.write_all(b"HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n")?;
+        return Ok(());
+    };
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: video/h264\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+    )?;
+    println!(
+        "Stream viewer {} connected to {}",
+        stream.peer_addr()?,
+        path
+    );
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let (tx, rx) = mpsc::sync_channel(VIEWER_QUEUE);
+    thread::spawn(move || send_to_viewer(stream, rx));
+    viewers
+        .clients
+        .lock()
+        .unwrap()
+        .push(Viewer { tx, waiting: true });
+    CONNECTED_CLIENTS.fetch_add(1, Ordering::Relaxed);
+    viewers.keyframe_requested.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+// Writes the queued access units until the viewer goes away.
+fn send_to_viewer(mut stream: TcpStream, rx: Receiver<Arc<Vec<u8>>>) {
+    while let Ok(access_unit) = rx.recv() {
+        if let Err(e) = stream.write_all(&access_unit) {
+            println!("Stream viewer disconnected: {}", e);
+            return;
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub fn start_stream(
+    path: &str,
+    width: u32,
+    height: u32,
+    frame_rate: u32,
+    stream_config: &config::StreamConfig,
+    metadata_rx: Option<Receiver<Vec<u8>>>,
+    forward: Option<Box<dyn FnMut(&[u8]) + Send>>,
+) -> NativeStream {
+    let viewers = Arc::new(Viewers::default());
+    STREAMS
+        .lock()
+        .unwrap()
+        .insert(path.to_string(), viewers.clone());
+
+    let config = EncoderConfig::new()
+        .bitrate(BitRate::from_bps(
+            stream_config.bitrate.map_or(8_000_000, |kbps| kbps * 1000),
+        ))
+        .max_frame_rate(FrameRate::from_hz(frame_rate as f32))
+        .intra_frame_period(IntraFramePeriod::from_num_frames(
+            stream_config.gop.unwrap_or(frame_rate),
+        ));
+    let encoder = Encoder::with_api_config(OpenH264API::from_source(), config)
+        .expect("Failed to create H.264 encoder");
+
+    let (frame_tx, frame_rx) = mpsc::sync_channel::<Vec<u8>>(1);
+    thread::spawn(move || {
+        run_encoder(
+            encoder,
+            frame_rx,
+            metadata_rx,
+            forward,
+            viewers,
+            (width as usize, height as usize),
+        );
+    });
+    NativeStream { frame_tx }
+}
+
+#[allow(clippy::type_complexity)]
+fn run_encoder(
+    mut encoder: Encoder,
+    frame_rx: Receiver<Vec<u8>>,
+    metadata_rx: Option<Receiver<Vec<u8>>>,
+    mut forward: Option<Box<dyn FnMut(&[u8]) + Send>>,
+    viewers: Arc<Viewers>,
+    size: (usize, usize),
+) {
+    while let Ok(frame) = frame_rx.recv() {
+        if viewers.keyframe_requested.swap(false, Ordering::Relaxed) {
+            encoder.force_intra_frame();
+        }
+        let yuv = YUVBuffer::from_rgb_source(BgraSliceU8::new(&frame, size));
+        let bitstream = match encoder.encode(&yuv) {
+            Ok(bitstream) => bitstream,
+            Err(e) => {
+                eprintln!("Failed to encode frame: {}", e);
+                continue;
+            }
+        };
+        let keyframe = matches!(bitstream.frame_type(), FrameType::IDR);
+
+        let mut access_unit = Vec::new();
+        // The telemetry SEI goes in front of the parameter sets and slices of the frame. Only
+        // the latest telemetry is used as frames may have been dropped.
+        if let Some(payload) = metadata_rx.as_ref().and_then(|rx| rx.try_iter().last()) {
+            access_unit.extend_from_slice(&klv::sei_nal(&payload));
+        }
+        bitstream.write_vec(&mut access_unit);
+        // Also hand the access unit to the built in RTSP server.
+        if let Some(forward) = forward.as_mut() {
+            forward(&access_unit);
+        }
+
+        let access_unit = Arc::new(access_unit);
+        let mut clients = viewers.clients.lock().unwrap();
+        clients.retain_mut(|viewer| {
+            viewer.waiting &= !keyframe;
+            if viewer.waiting {
+                return true;
+            }
+            match viewer.tx.try_send(access_unit.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    viewer.waiting = true;
+                    viewers.keyframe_requested.store(true, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    CONNECTED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+                    false
+                }
+            }
+        });
+    }
+}
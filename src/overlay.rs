@@ -0,0 +1,278 @@
+use crate::config;
+use crate::post_process;
+use crate::radar;
+use crate::radar_cam;
+use crate::simulation;
+use bevy::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Fraction of the image width covered by the heading tape.
+const TAPE_WIDTH: f32 = 0.6;
+const TAPE_STEP_DEG: u32 = 5;
+
+#[derive(Component)]
+pub struct OverlayTelemetry;
+
+#[derive(Component)]
+pub struct HeadingTick {
+    heading: f32,
+}
+
+pub fn spawn_overlay(
+    mut commands: Commands,
+    config: Res<config::Config>,
+    camera_query: Query<Entity, With<radar_cam::RadarCamera>>,
+    post_process_query: Query<Entity, With<post_process::PostProcessCamera>>,
+) {
+    let Some(overlay) = &config.overlay else {
+        return;
+    };
+    // Draw on the final image so the overlay is not distorted by the lens.
+    let Ok(camera) = post_process_query.single().or(camera_query.single()) else {
+        eprintln!("No radar camera to draw the overlay on");
+        return;
+    };
+    let [r, g, b] = overlay.color;
+    let color = Color::linear_rgb(r, g, b);
+    let font = TextFont {
+        font_size: overlay.font_size,
+        ..default()
+    };
+
+    let mut root = commands.spawn((
+        Node {
+            width: Val::Percent(100.0),
+            height: Val::Percent(100.0),
+            ..default()
+        },
+        UiTargetCamera(camera),
+    ));
+
+    if overlay.crosshair {
+        let line = overlay.font_size * 2.0;
+        let thickness = (overlay.font_size / 10.0).max(1.0);
+        root.with_child((
+            Node {
+                position_type: PositionType::Absolute,
+                left: Val::Percent(50.0),
+                top: Val::Percent(50.0),
+                width: Val::Px(line),
+                height: Val::Px(thickness),
+                margin: UiRect::new(
+                    Val::Px(-line / 2.0),
+                    Val::ZERO,
+                    Val::Px(-thickness / 2.0),
+                    Val::ZERO,
+                ),
+                ..default()
+            },
+            BackgroundColor(color),
+        ));
+        root.with_child((
+            Node {
+                position_type: PositionType::Absolute,
+                left: Val::Percent(50.0),
+                top: Val::Percent(50.0),
+                width: Val::Px(thickness),
+                height: Val::Px(line),
+                margin: UiRect::new(
+                    Val::Px(-thickness / 2.0),
+                    Val::ZERO,
+                    Val::Px(-line / 2.0),
+                    Val::ZERO,
+                ),
+                ..default()
+            },
+            BackgroundColor(color),
+        ));
+    }
+
+    if overlay.telemetry {
+        root.with_child((
+            Node {
+                position_type: PositionType::Absolute,
+                left: Val::Px(overlay.font_size),
+                bottom: Val::Px(overlay.font_size),
+                ..default()
+            },
+            Text::new(""),
+            font.clone(),
+            TextColor(color),
+            OverlayTelemetry,
+        ));
+    }
+
+    if overlay.heading_tape {
+        root.with_children(|parent| {
+            parent
+                .spawn(Node {
+                    position_type: PositionType::Absolute,
+                    left: Val::Percent((1.0 - TAPE_WIDTH) * 50.0),
+                    top: Val::Px(overlay.font_size / 2.0),
+                    width: Val::Percent(TAPE_WIDTH * 100.0),
+                    height: Val::Px(overlay.font_size * 2.5),
+                    overflow: Overflow::clip(),
+                    ..default()
+                })
+                .with_children(|tape| {
+                    // Center marker
+                    tape.spawn((
+                        Node {
+                            position_type: PositionType::Absolute,
+                            left: Val::Percent(50.0),
+                            bottom: Val::ZERO,
+                            width: Val::Px(3.0),
+                            height: Val::Percent(100.0),
+                            ..default()
+                        },
+                        BackgroundColor(color.with_alpha(0.5)),
+                    ));
+                    for heading in (0..360).step_by(TAPE_STEP_DEG as usize) {
+                        let major = heading % (2 * TAPE_STEP_DEG) == 0;
+                        tape.spawn((
+                            Node {
+                                position_type: PositionType::Absolute,
+                                bottom: Val::ZERO,
+                                flex_direction: FlexDirection::Column,
+                                align_items: AlignItems::Center,
+                                ..default()
+                            },
+                            Visibility::Hidden,
+                            HeadingTick {
+                                heading: heading as f32,
+                            },
+                        ))
+                        .with_children(|tick| {
+                            if major {
+                                tick.spawn((
+                                    Text::new(format!("{:03}", heading)),
+                                    font.clone(),
+                                    TextColor(color),
+                                ));
+                            }
+                            tick.spawn((
+                                Node {
+                                    width: Val::Px(2.0),
+                                    height: Val::Px(
+                                        overlay.font_size * if major { 0.6 } else { 0.3 },
+                                    ),
+                                    ..default()
+                                },
+                                BackgroundColor(color),
+                            ));
+                        });
+                    }
+                });
+        });
+    }
+}
+
+pub fn update_overlay(
+    radar: Res<radar::Radar>,
+    time: Res<Time>,
+    simulation: Res<simulation::Simulation>,
+    camera_query: Query<(&Camera, &Projection), With<radar_cam::RadarCamera>>,
+    mut text_query: Query<&mut Text, With<OverlayTelemetry>>,
+    mut tick_query: Query<(&HeadingTick, &mut Node, &mut Visibility, &ComputedNode)>,
+) {
+    let Ok((camera, Projection::Perspective(perspective))) = camera_query.single() else {
+        return;
+    };
+    let aspect_ratio = camera
+        .logical_target_size()
+        .map(|size| size.x / size.y)
+        .unwrap_or(perspective.aspect_ratio);
+    let hfov = (2.0 * ((perspective.fov / 2.0).tan() * aspect_ratio).atan()).to_degrees();
+    let vfov = perspective.fov.to_degrees();
+    let azimuth = radar.current.azimuth.rem_euclid(360.0);
+
+    for mut text in text_query.iter_mut() {
+        text.0 = format!(
+            "AZ {:>6.2}  EL {:>6.2}\nFOV {:>5.2} x {:>5.2}\n{}\nSIM {}",
+            azimuth,
+            radar.current.elevation,
+            hfov,
+            vfov,
+            format_utc(simulation.now(time.elapsed())),
+            simulation.describe(time.elapsed()),
+        );
+    }
+
+    // The tape spans the same angle as the image width it covers.
+    let span = hfov * TAPE_WIDTH;
+    for (tick, mut node, mut visibility, computed) in tick_query.iter_mut() {
+        let mut offset = tick.heading - azimuth;
+        if offset > 180.0 {
+            offset -= 360.0;
+        } else if offset < -180.0 {
+            offset += 360.0;
+        }
+        if offset.abs() > span / 2.0 {
+            *visibility = Visibility::Hidden;
+            continue;
+        }
+        *visibility = Visibility::Inherited;
+        let half_width = computed.size().x * computed.inverse_scale_factor() / 2.0;
+        node.left = Val::Percent(50.0 + offset / span * 100.0);
+        node.margin.left = Val::Px(-half_width);
+    }
+}
+
+fn format_utc(time: SystemTime) -> String {
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0);
+    let days = millis.div_euclid(86_400_000);
+    let ms_of_day = millis.rem_euclid(86_400_000);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        ms_of_day / 3_600_000,
+        ms_of_day / 60_000 % 60,
+        ms_of_day / 1000 % 60,
+        ms_of_day % 1000,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn utc(millis: u64) -> String {
+        format_utc(UNIX_EPOCH + Duration::from_millis(millis))
+    }
+
+    #[test]
+    fn formats_the_epoch() {
+        assert_eq!(utc(0), "1970-01-01 00:00:00.000Z");
+    }
+
+    #[test]
+    fn formats_leap_days() {
+        assert_eq!(utc(1_709_210_096_789), "2024-02-29 12:34:56.789Z");
+        // Divisible by 400, so a leap year
+        assert_eq!(utc(951_782_400_000), "2000-02-29 00:00:00.000Z");
+        // Divisible by 100 only, so February has 28 days
+        assert_eq!(utc(4_107_542_400_000), "2100-03-01 00:00:00.000Z");
+    }
+
+    #[test]
+    fn formats_a_year_boundary() {
+        assert_eq!(utc(946_684_799_999), "1999-12-31 23:59:59.999Z");
+        assert_eq!(utc(946_684_800_000), "2000-01-01 00:00:00.000Z");
+    }
+}
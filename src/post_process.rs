@@ -0,0 +1,298 @@
+use crate::config;
+use crate::radar;
+use crate::radar_cam;
+use crate::simulation;
+use crate::stream;
+use bevy::camera::visibility::RenderLayers;
+use bevy::prelude::*;
+use bevy::render::render_resource::{AsBindGroup, ShaderType};
+use bevy::shader::ShaderRef;
+use bevy::sprite_render::{Material2d, MeshMaterial2d};
+use serde::Serialize;
+use std::fs;
+
+const SHADER_ASSET_PATH: &str = "shaders/post_process.wgsl";
+// Keeps the fullscreen quad out of every camera except the post process camera.
+const POST_PROCESS_LAYER: usize = 31;
+// How fast auto exposure closes the gap to the target brightness, on a log scale per second.
+const EXPOSURE_ADAPTATION: f32 = 3.0;
+
+#[derive(Component)]
+pub struct PostProcessCamera;
+
+#[derive(Component)]
+pub struct PostProcessQuad(Handle<PostProcessMaterial>);
+
+#[derive(ShaderType, Debug, Clone, Copy, Default)]
+pub struct LensUniform {
+    pub image_size: Vec2,
+    pub focal_length: Vec2,
+    pub principal_point: Vec2,
+    // k1, k2, k3
+    pub radial: Vec4,
+    // p1, p2
+    pub tangential: Vec4,
+}
+
+#[derive(ShaderType, Debug, Clone, Copy, Default)]
+pub struct SensorUniform {
+    // Pixel displacement of the scene during the exposure.
+    pub blur: Vec2,
+    // Pixel displacement of the scene between the first and last row read out.
+    pub rolling_shutter: Vec2,
+    pub gain: f32,
+    pub noise: f32,
+    pub seed: f32,
+}
+
+#[derive(Asset, TypePath, AsBindGroup, Debug, Clone)]
+pub struct PostProcessMaterial {
+    #[uniform(0)]
+    pub lens: LensUniform,
+    #[texture(1)]
+    #[sampler(2)]
+    pub source: Handle<Image>,
+    #[uniform(3)]
+    pub sensor: SensorUniform,
+}
+
+impl Material2d for PostProcessMaterial {
+    fn fragment_shader() -> ShaderRef {
+        SHADER_ASSET_PATH.into()
+    }
+}
+
+// Ground truth camera model, using the OpenCV conventions.
+#[derive(Resource, Debug, Clone)]
+pub struct LensModel {
+    pub width: f32,
+    pub height: f32,
+    pub principal_point_offset: Vec2,
+    pub radial: [f32; 3],
+    pub tangential: [f32; 2],
+    intrinsics_file: Option<String>,
+    exported_fov: Option<f32>,
+}
+
+#[derive(Serialize)]
+struct IntrinsicsExport {
+    width: u32,
+    height: u32,
+    vertical_fov: f32,
+    camera_matrix: [[f32; 3]; 3],
+    // k1, k2, p1, p2, k3
+    distortion: [f32; 5],
+}
+
+impl LensModel {
+    // Without a lens config the model is an ideal pinhole camera.
+    pub fn new(config: Option<&config::LensConfig>, width: u32, height: u32) -> Self {
+        Self {
+            width: width as f32,
+            height: height as f32,
+            principal_point_offset: config
+                .map(|c| Vec2::from_array(c.principal_point_offset))
+                .unwrap_or_default(),
+            radial: config.map(|c| c.radial).unwrap_or_default(),
+            tangential: config.map(|c| c.tangential).unwrap_or_default(),
+            intrinsics_file: config.and_then(|c| c.intrinsics_file.clone()),
+            exported_fov: None,
+        }
+    }
+
+    pub fn focal_length(&self, vertical_fov: f32) -> f32 {
+        (self.height / 2.0) / (vertical_fov.to_radians() / 2.0).tan()
+    }
+
+    pub fn principal_point(&self) -> Vec2 {
+        Vec2::new(self.width, self.height) / 2.0 + self.principal_point_offset
+    }
+
+    pub fn camera_matrix(&self, vertical_fov: f32) -> [[f32; 3]; 3] {
+        let f = self.focal_length(vertical_fov);
+        let c = self.principal_point();
+        [[f, 0.0, c.x], [0.0, f, c.y], [0.0, 0.0, 1.0]]
+    }
+
+    pub fn distortion(&self) -> [f32; 5] {
+        let [k1, k2, k3] = self.radial;
+        let [p1, p2] = self.tangential;
+        [k1, k2, p1, p2, k3]
+    }
+
+    // Maps a pixel of the ideal pinhole render to where it lands in the distorted image.
+    pub fn distort_pixel(&self, pixel: Vec2, vertical_fov: f32) -> Vec2 {
+        let f = self.focal_length(vertical_fov);
+        let p = (pixel - Vec2::new(self.width, self.height) / 2.0) / f;
+        let [k1, k2, k3] = self.radial;
+        let [p1, p2] = self.tangential;
+        let r2 = p.length_squared();
+        let radial = 1.0 + k1 * r2 + k2 * r2 * r2 + k3 * r2 * r2 * r2;
+        let d = Vec2::new(
+            p.x * radial + 2.0 * p1 * p.x * p.y + p2 * (r2 + 2.0 * p.x * p.x),
+            p.y * radial + p1 * (r2 + 2.0 * p.y * p.y) + 2.0 * p2 * p.x * p.y,
+        );
+        d * f + self.principal_point()
+    }
+
+    fn uniform(&self, vertical_fov: f32) -> LensUniform {
+        let [k1, k2, k3] = self.radial;
+        let [p1, p2] = self.tangential;
+        LensUniform {
+            image_size: Vec2::new(self.width, self.height),
+            focal_length: Vec2::splat(self.focal_length(vertical_fov)),
+            principal_point: self.principal_point(),
+            radial: Vec4::new(k1, k2, k3, 0.0),
+            tangential: Vec4::new(p1, p2, 0.0, 0.0),
+        }
+    }
+
+    fn export(&self, vertical_fov: f32) {
+        let Some(path) = &self.intrinsics_file else {
+            return;
+        };
+        let export = IntrinsicsExport {
+            width: self.width as u32,
+            height: self.height as u32,
+            vertical_fov,
+            camera_matrix: self.camera_matrix(vertical_fov),
+            distortion: self.distortion(),
+        };
+        match serde_json::to_string_pretty(&export) {
+            Ok(json) => {
+                if let Err(e) = fs::write(path, json) {
+                    eprintln!("Failed to write intrinsics to {}: {}", path, e);
+                }
+            }
+            Err(e) => eprintln!("Failed to serialize intrinsics: {}", e),
+        }
+    }
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct SensorModel {
+    pub noise: f32,
+    pub auto_exposure: bool,
+    pub exposure_target: f32,
+    pub exposure_time: f32,
+    pub readout_time: f32,
+    pub gain: f32,
+    // Seconds between frames of the radar camera stream
+    frame_time: f32,
+    // Stream frame the gain was last adapted to
+    exposed_frame: u64,
+}
+
+impl SensorModel {
+    // Without a sensor config the sensor is ideal and leaves the image untouched.
+    pub fn new(config: Option<&config::SensorConfig>, frame_rate: u32) -> Self {
+        Self {
+            noise: config.map(|c| c.noise).unwrap_or(0.0),
+            auto_exposure: config.map(|c| c.auto_exposure).unwrap_or(false),
+            exposure_target: config.map(|c| c.exposure_target).unwrap_or(0.45),
+            exposure_time: config.map(|c| c.exposure_time).unwrap_or(0.0),
+            readout_time: config.map(|c| c.readout_time).unwrap_or(0.0),
+            gain: 1.0,
+            frame_time: 1.0 / frame_rate as f32,
+            exposed_frame: 0,
+        }
+    }
+
+    fn uniform(&self, radar: &radar::Radar, focal_length: f32, seed: f32) -> SensorUniform {
+        // Panning right moves the scene left and tilting up moves it down in the image.
+        let pixels_per_degree = focal_length * 1f32.to_radians().tan();
+        let velocity =
+            Vec2::new(radar.azimuth_velocity, -radar.elevation_velocity) * pixels_per_degree;
+        SensorUniform {
+            blur: velocity * self.exposure_time,
+            rolling_shutter: velocity * self.readout_time,
+            gain: self.gain,
+            noise: self.noise,
+            seed,
+        }
+    }
+}
+
+// Renders the camera image through the post process shader into a new image of the same
+// size and returns it. The camera keeps rendering into `source`.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_post_process(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    images: &mut Assets<Image>,
+    materials: &mut Assets<PostProcessMaterial>,
+    source: Handle<Image>,
+    lens: &LensModel,
+    sensor: &SensorModel,
+    zoom: &radar_cam::Zoom,
+) -> Handle<Image> {
+    let output = radar_cam::render_target(images, lens.width as u32, lens.height as u32);
+
+    let material = materials.add(PostProcessMaterial {
+        lens: lens.uniform(zoom.current_fov),
+        source,
+        sensor: SensorUniform {
+            gain: sensor.gain,
+            ..default()
+        },
+    });
+    commands.spawn((
+        Mesh2d(meshes.add(Rectangle::new(lens.width, lens.height))),
+        MeshMaterial2d(material.clone()),
+        PostProcessQuad(material),
+        RenderLayers::layer(POST_PROCESS_LAYER),
+    ));
+    commands.spawn((
+        Camera2d,
+        Camera {
+            target: output.clone().into(),
+            order: 2,
+            ..default()
+        },
+        RenderLayers::layer(POST_PROCESS_LAYER),
+        PostProcessCamera,
+    ));
+    output
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_post_process(
+    zoom: Res<radar_cam::Zoom>,
+    radar: Res<radar::Radar>,
+    time: Res<Time>,
+    frame_buffer: Res<stream::FrameBuffer>,
+    simulation: Res<simulation::Simulation>,
+    mut lens: ResMut<LensModel>,
+    mut sensor: ResMut<SensorModel>,
+    mut materials: ResMut<Assets<PostProcessMaterial>>,
+    query: Query<&PostProcessQuad>,
+) {
+    let frame = frame_buffer.info();
+    // Stepped runs only adapt on ticks so the gain is the same on every run.
+    let ticked = !simulation.deterministic || time.delta_secs() > 0.0;
+    if sensor.auto_exposure && ticked && frame.number > sensor.exposed_frame {
+        // Nudge the gain towards the target brightness once per streamed frame, by as
+        // much as the frames since the last one allow.
+        let frame_time = (frame.number - sensor.exposed_frame) as f32 * sensor.frame_time;
+        let rate = (EXPOSURE_ADAPTATION * frame_time).min(1.0);
+        let mean = frame.luminance.max(1.0 / 255.0);
+        let gain = sensor.gain * (sensor.exposure_target / mean).powf(rate);
+        sensor.gain = gain.clamp(0.1, 10.0);
+        sensor.exposed_frame = frame.number;
+    }
+    for quad in query.iter() {
+        if let Some(material) = materials.get_mut(&quad.0) {
+            material.lens = lens.uniform(zoom.current_fov);
+            material.sensor = sensor.uniform(
+                &radar,
+                lens.focal_length(zoom.current_fov),
+                time.elapsed_secs() % 1000.0,
+            );
+        }
+    }
+    // Export the intrinsics once the zoom has settled on a new fov.
+    if lens.exported_fov != Some(zoom.current_fov) && zoom.current_fov == zoom.target_fov {
+        lens.export(zoom.current_fov);
+        lens.exported_fov = Some(zoom.current_fov);
+    }
+}
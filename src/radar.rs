@@ -1,12 +1,25 @@
 use crate::config;
+use crate::metrics;
+use crate::post_process;
+use crate::radar_cam;
+use crate::recording;
+use crate::simulation;
+use crate::snapshot;
+use crate::stream;
+use crate::supervisor;
+use crate::thermal;
 use bevy::prelude::*;
 use std::f32::consts::PI;
 use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::Mutex;
 use std::thread;
+use std::time::Instant;
+
+static CONNECTED_CLIENTS: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Resource)]
 pub struct CommandReceiver {
@@ -89,6 +102,7 @@ pub fn spawn_radar(
         receiver: Mutex::new(cmd_rx),
     });
 
+    metrics::register_clients("command", || CONNECTED_CLIENTS.load(Ordering::Relaxed));
     thread::spawn(move || {
         run_tcp_listener(cmd_tx);
     });
@@ -143,12 +157,68 @@ pub fn spawn_radar(
 }
 
 pub enum RadarCommand {
-    Remote { tx: Sender<String> },
-    ServoOn { tx: Sender<String> },
-    Azimuth { az: f32, tx: Sender<String> },
-    Elevation { el: f32, tx: Sender<String> },
-    AzimuthQuery { tx: Sender<String> },
-    ElevationQuery { tx: Sender<String> },
+    Remote {
+        tx: Sender<String>,
+    },
+    ServoOn {
+        tx: Sender<String>,
+    },
+    Azimuth {
+        az: f32,
+        tx: Sender<String>,
+    },
+    Elevation {
+        el: f32,
+        tx: Sender<String>,
+    },
+    AzimuthQuery {
+        tx: Sender<String>,
+    },
+    ElevationQuery {
+        tx: Sender<String>,
+    },
+    Zoom {
+        fov: f32,
+        tx: Sender<String>,
+    },
+    FovQuery {
+        tx: Sender<String>,
+    },
+    IntrinsicsQuery {
+        tx: Sender<String>,
+    },
+    Palette {
+        palette: thermal::Palette,
+        tx: Sender<String>,
+    },
+    Status {
+        tx: Sender<String>,
+    },
+    // Starts or stops recording, None queries it
+    Record {
+        start: Option<bool>,
+        tx: Sender<String>,
+    },
+    // Pauses or resumes the simulation
+    Pause {
+        paused: bool,
+        tx: Sender<String>,
+    },
+    // Sets how fast the simulation runs, None queries it
+    Speed {
+        speed: Option<f32>,
+        tx: Sender<String>,
+    },
+    // Runs the deterministic simulation for ticks more ticks
+    Step {
+        ticks: u64,
+        tx: Sender<String>,
+    },
+    // Saves a still of the radar camera, in the configured format when None
+    Snapshot {
+        format: Option<config::ImageFormat>,
+        tx: Sender<String>,
+    },
 }
 
 fn run_tcp_listener(cmd_tx: Sender<RadarCommand>) {
@@ -159,8 +229,10 @@ fn run_tcp_listener(cmd_tx: Sender<RadarCommand>) {
             Ok(stream) => {
                 // For each connection, clone the sender and spawn a thread.
                 let tx_clone = cmd_tx.clone();
+                CONNECTED_CLIENTS.fetch_add(1, Ordering::Relaxed);
                 thread::spawn(move || {
                     handle_client(stream, tx_clone);
+                    CONNECTED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
                 });
             }
             Err(e) => {
@@ -190,76 +262,143 @@ fn handle_client(mut stream: TcpStream, cmd_tx: Sender<RadarCommand>) {
                         // Remove \r
                         let line = command_str.trim().to_uppercase();
 
+                        let parts: Vec<&str> = line.split_whitespace().collect();
                         if line.starts_with("AZIMUTH") {
-                            let parts: Vec<&str> = line.split_whitespace().collect();
                             if parts.len() == 2 {
-                                let (reply_tx, reply_rx) = mpsc::channel();
                                 if let Ok(az) = parts[1].parse::<f32>() {
-                                    let cmd = RadarCommand::Azimuth { az, tx: reply_tx };
-                                    if let Err(e) = cmd_tx.send(cmd) {
-                                        eprintln!("Failed to send AZIMUTH command: {:?}", e);
-                                    } else {
-                                        if let Ok(o) = reply_rx.recv() {
-                                            let _ = stream.write_all(o.as_bytes());
-                                        }
-                                    }
+                                    send_command(&mut stream, &cmd_tx, "AZIMUTH", |tx| {
+                                        RadarCommand::Azimuth { az, tx }
+                                    });
                                 }
                             } else if parts.len() == 1 {
-                                let (reply_tx, reply_rx) = mpsc::channel();
-                                let cmd = RadarCommand::AzimuthQuery { tx: reply_tx };
-                                if let Err(e) = cmd_tx.send(cmd) {
-                                    eprintln!("Failed to send AZIMUTH QUERY command: {:?}", e);
-                                } else {
-                                    if let Ok(response) = reply_rx.recv() {
-                                        let _ = stream.write_all(response.as_bytes());
-                                    }
-                                }
+                                send_command(&mut stream, &cmd_tx, "AZIMUTH QUERY", |tx| {
+                                    RadarCommand::AzimuthQuery { tx }
+                                });
                             }
                         } else if line.starts_with("ELEVATION") {
-                            let parts: Vec<&str> = line.split_whitespace().collect();
                             if parts.len() == 2 {
-                                let (reply_tx, reply_rx) = mpsc::channel();
                                 if let Ok(el) = parts[1].parse::<f32>() {
-                                    let cmd = RadarCommand::Elevation { el, tx: reply_tx };
-                                    if let Err(e) = cmd_tx.send(cmd) {
-                                        eprintln!("Failed to send ELEVATION command: {:?}", e);
-                                    } else {
-                                        if let Ok(o) = reply_rx.recv() {
-                                            let _ = stream.write_all(o.as_bytes());
-                                        }
-                                    }
+                                    send_command(&mut stream, &cmd_tx, "ELEVATION", |tx| {
+                                        RadarCommand::Elevation { el, tx }
+                                    });
                                 }
                             } else if parts.len() == 1 {
-                                let (reply_tx, reply_rx) = mpsc::channel();
-                                let cmd = RadarCommand::ElevationQuery { tx: reply_tx };
-                                if let Err(e) = cmd_tx.send(cmd) {
-                                    eprintln!("Failed to send ELEVATION QUERY command: {:?}", e);
-                                } else {
-                                    if let Ok(response) = reply_rx.recv() {
-                                        let _ = stream.write_all(response.as_bytes());
+                                send_command(&mut stream, &cmd_tx, "ELEVATION QUERY", |tx| {
+                                    RadarCommand::ElevationQuery { tx }
+                                });
+                            }
+                        } else if line.starts_with("ZOOM") || line.starts_with("FOV") {
+                            if parts.len() == 2 {
+                                match parts[1].parse::<f32>().ok().filter(|fov| fov.is_finite()) {
+                                    Some(fov) => send_command(&mut stream, &cmd_tx, "ZOOM", |tx| {
+                                        RadarCommand::Zoom { fov, tx }
+                                    }),
+                                    None => {
+                                        let _ = stream.write_all(b"Invalid argument\r\n");
                                     }
                                 }
+                            } else if parts.len() == 1 {
+                                send_command(&mut stream, &cmd_tx, "FOV QUERY", |tx| {
+                                    RadarCommand::FovQuery { tx }
+                                });
                             }
-                        } else if line.starts_with("REMOTE") {
-                            let (reply_tx, reply_rx) = mpsc::channel();
-                            let cmd = RadarCommand::Remote { tx: reply_tx };
-                            if let Err(e) = cmd_tx.send(cmd) {
-                                eprintln!("Failed to send REMOTE command: {:?}", e);
-                            } else {
-                                if let Ok(o) = reply_rx.recv() {
-                                    let _ = stream.write_all(o.as_bytes());
+                        } else if line.starts_with("INTRINSICS") {
+                            send_command(&mut stream, &cmd_tx, "INTRINSICS", |tx| {
+                                RadarCommand::IntrinsicsQuery { tx }
+                            });
+                        } else if line.starts_with("PALETTE") {
+                            match parts.get(1).and_then(|p| thermal::Palette::parse(p)) {
+                                Some(palette) => {
+                                    send_command(&mut stream, &cmd_tx, "PALETTE", |tx| {
+                                        RadarCommand::Palette { palette, tx }
+                                    })
+                                }
+                                None => {
+                                    let _ = stream.write_all(b"Invalid argument\r\n");
                                 }
                             }
-                        } else if line.starts_with("SERVOON") {
-                            let (reply_tx, reply_rx) = mpsc::channel();
-                            let cmd = RadarCommand::ServoOn { tx: reply_tx };
-                            if let Err(e) = cmd_tx.send(cmd) {
-                                eprintln!("Failed to send SERVOON command: {:?}", e);
-                            } else {
-                                if let Ok(o) = reply_rx.recv() {
-                                    let _ = stream.write_all(o.as_bytes());
+                        } else if line.starts_with("STATUS") {
+                            send_command(&mut stream, &cmd_tx, "STATUS", |tx| {
+                                RadarCommand::Status { tx }
+                            });
+                        } else if line.starts_with("RECORD") {
+                            let start = match parts.get(1) {
+                                Some(&"START") => Some(Some(true)),
+                                Some(&"STOP") => Some(Some(false)),
+                                None => Some(None),
+                                Some(_) => None,
+                            };
+                            match start {
+                                Some(start) => send_command(&mut stream, &cmd_tx, "RECORD", |tx| {
+                                    RadarCommand::Record { start, tx }
+                                }),
+                                None => {
+                                    let _ = stream.write_all(b"Invalid argument\r\n");
+                                }
+                            }
+                        } else if line.starts_with("PAUSE") || line.starts_with("RESUME") {
+                            let paused = line.starts_with("PAUSE");
+                            send_command(&mut stream, &cmd_tx, "PAUSE", |tx| RadarCommand::Pause {
+                                paused,
+                                tx,
+                            });
+                        } else if line.starts_with("SPEED") {
+                            let speed = match parts.get(1) {
+                                Some(speed) => speed
+                                    .parse::<f32>()
+                                    .ok()
+                                    .filter(|speed| speed.is_finite())
+                                    .map(Some),
+                                None => Some(None),
+                            };
+                            match speed {
+                                Some(speed) => send_command(&mut stream, &cmd_tx, "SPEED", |tx| {
+                                    RadarCommand::Speed { speed, tx }
+                                }),
+                                None => {
+                                    let _ = stream.write_all(b"Invalid argument\r\n");
                                 }
                             }
+                        } else if line.starts_with("STEP") {
+                            let ticks = match parts.get(1) {
+                                Some(ticks) => ticks.parse::<u64>().ok(),
+                                None => Some(1),
+                            };
+                            match ticks {
+                                Some(ticks) => send_command(&mut stream, &cmd_tx, "STEP", |tx| {
+                                    RadarCommand::Step { ticks, tx }
+                                }),
+                                None => {
+                                    let _ = stream.write_all(b"Invalid argument\r\n");
+                                }
+                            }
+                        } else if line.starts_with("SNAPSHOT") {
+                            let format = match parts.get(1) {
+                                Some(&"PNG") => Some(Some(config::ImageFormat::Png)),
+                                Some(&"JPEG") | Some(&"JPG") => {
+                                    Some(Some(config::ImageFormat::Jpeg))
+                                }
+                                None => Some(None),
+                                Some(_) => None,
+                            };
+                            match format {
+                                Some(format) => {
+                                    send_command(&mut stream, &cmd_tx, "SNAPSHOT", |tx| {
+                                        RadarCommand::Snapshot { format, tx }
+                                    })
+                                }
+                                None => {
+                                    let _ = stream.write_all(b"Invalid argument\r\n");
+                                }
+                            }
+                        } else if line.starts_with("REMOTE") {
+                            send_command(&mut stream, &cmd_tx, "REMOTE", |tx| {
+                                RadarCommand::Remote { tx }
+                            });
+                        } else if line.starts_with("SERVOON") {
+                            send_command(&mut stream, &cmd_tx, "SERVOON", |tx| {
+                                RadarCommand::ServoOn { tx }
+                            });
                         } else {
                             let _ = stream.write_all(b"Unknown command\n");
                         }
@@ -276,7 +415,37 @@ fn handle_client(mut stream: TcpStream, cmd_tx: Sender<RadarCommand>) {
     }
 }
 
-pub fn handle_commands(mut radar: ResMut<Radar>, cmd_receiver: ResMut<CommandReceiver>) {
+// Forwards a command to the app and writes the reply back to the client.
+fn send_command(
+    stream: &mut TcpStream,
+    cmd_tx: &Sender<RadarCommand>,
+    name: &str,
+    command: impl FnOnce(Sender<String>) -> RadarCommand,
+) {
+    let (reply_tx, reply_rx) = mpsc::channel();
+    let sent = Instant::now();
+    if let Err(e) = cmd_tx.send(command(reply_tx)) {
+        eprintln!("Failed to send {} command: {:?}", name, e);
+    } else if let Ok(response) = reply_rx.recv() {
+        metrics::record_command(sent.elapsed());
+        let _ = stream.write_all(response.as_bytes());
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_commands(
+    mut radar: ResMut<Radar>,
+    mut zoom: ResMut<radar_cam::Zoom>,
+    lens: Res<post_process::LensModel>,
+    mut thermal: Option<ResMut<thermal::Thermal>>,
+    mut recorder: ResMut<recording::Recorder>,
+    streams: Query<&stream::CameraStream>,
+    frame_buffer: Res<stream::FrameBuffer>,
+    config: Res<config::Config>,
+    mut simulation: ResMut<simulation::Simulation>,
+    time: Res<Time>,
+    cmd_receiver: ResMut<CommandReceiver>,
+) {
     let receiver = cmd_receiver.receiver.lock().unwrap();
     while let Ok(command) = receiver.try_recv() {
         match command {
@@ -305,6 +474,118 @@ pub fn handle_commands(mut radar: ResMut<Radar>, cmd_receiver: ResMut<CommandRec
                 let s = format!("{:.2}\r\n", radar.current.elevation);
                 let _ = tx.send(s);
             }
+            RadarCommand::Zoom { fov, tx } => {
+                println!("Setting fov to {:.2}", fov);
+                zoom.target_fov = fov.clamp(zoom.min_fov, zoom.max_fov);
+                let _ = tx.send("No Errors.\r\n".to_string());
+            }
+            RadarCommand::FovQuery { tx } => {
+                let s = format!("{:.2}\r\n", zoom.current_fov);
+                let _ = tx.send(s);
+            }
+            RadarCommand::Palette { palette, tx } => match thermal.as_mut() {
+                Some(thermal) => {
+                    println!("Setting thermal palette to {:?}", palette);
+                    thermal.palette = palette;
+                    let _ = tx.send("No Errors.\r\n".to_string());
+                }
+                None => {
+                    let _ = tx.send("No thermal camera.\r\n".to_string());
+                }
+            },
+            RadarCommand::Status { tx } => {
+                // Pedestal state on the first line, then one line per stream and per
+                // supervised process.
+                let mut s = format!(
+                    "azimuth {:.2} elevation {:.2} fov {:.2} time {}\r\n",
+                    radar.current.azimuth,
+                    radar.current.elevation,
+                    zoom.current_fov,
+                    simulation.describe(time.elapsed())
+                );
+                for stream in &streams {
+                    s += &format!(
+                        "stream {} frame {} {}\r\n",
+                        stream.path,
+                        stream.frame_number,
+                        stream.stats.summary()
+                    );
+                }
+                for health in supervisor::health() {
+                    s += &format!("process {}\r\n", health);
+                }
+                let _ = tx.send(s);
+            }
+            RadarCommand::Record { start, tx } => {
+                let s = match start {
+                    Some(true) => match recorder.start() {
+                        Ok(path) => format!("Recording to {}\r\n", path.display()),
+                        Err(e) => format!("Failed to start recording: {}\r\n", e),
+                    },
+                    Some(false) => match recorder.stop(false) {
+                        Some(path) => format!("Stopped recording {}\r\n", path.display()),
+                        None => "Not recording.\r\n".to_string(),
+                    },
+                    None => match recorder.video_path() {
+                        Some(path) => format!("Recording to {}\r\n", path.display()),
+                        None => "Not recording.\r\n".to_string(),
+                    },
+                };
+                let _ = tx.send(s);
+            }
+            RadarCommand::Pause { tx, .. } | RadarCommand::Speed { tx, .. }
+                if simulation.deterministic =>
+            {
+                let _ =
+                    tx.send("Only STEP runs the simulation in deterministic mode.\r\n".to_string());
+            }
+            RadarCommand::Pause { paused, tx } => {
+                println!("{} simulation", if paused { "Pausing" } else { "Resuming" });
+                simulation.paused = paused;
+                let _ = tx.send("No Errors.\r\n".to_string());
+            }
+            RadarCommand::Speed { speed, tx } => {
+                if let Some(speed) = speed {
+                    simulation.set_speed(speed);
+                    println!("Setting simulation speed to {:.2}", simulation.speed);
+                }
+                let _ = tx.send(format!("{:.2}\r\n", simulation.speed));
+            }
+            RadarCommand::Step { ticks, tx } => simulation.step(ticks, tx),
+            RadarCommand::Snapshot { format, tx } => {
+                // Encoding takes longer than a frame, so it happens off the main thread.
+                let frame_buffer = frame_buffer.clone();
+                let config = config.snapshot.clone();
+                thread::spawn(move || {
+                    let format = format.unwrap_or(config.format);
+                    let saved = snapshot::Snapshot::take(&frame_buffer, format, config.quality)
+                        .and_then(|snapshot| Ok((snapshot.save(&config.directory)?, snapshot)));
+                    let s = match saved {
+                        Ok((path, snapshot)) => {
+                            println!("Saved snapshot {}", path.display());
+                            format!(
+                                "Snapshot {} frame {} azimuth {:.2} elevation {:.2}\r\n",
+                                path.display(),
+                                snapshot.info.frame,
+                                snapshot.info.azimuth,
+                                snapshot.info.elevation
+                            )
+                        }
+                        Err(e) => format!("Failed to take snapshot: {}\r\n", e),
+                    };
+                    let _ = tx.send(s);
+                });
+            }
+            RadarCommand::IntrinsicsQuery { tx } => {
+                // fx fy cx cy k1 k2 p1 p2 k3
+                let k = lens.camera_matrix(zoom.current_fov);
+                let d = lens.distortion();
+                let s = format!(
+                    "{:.3} {:.3} {:.3} {:.3} {} {} {} {} {}\r\n",
+                    k[0][0], k[1][1], k[0][2], k[1][2], d[0], d[1], d[2], d[3], d[4]
+                );
+                let _ = tx.send(s);
+            }
         }
     }
 }
@@ -7,21 +7,45 @@ use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat, T
 #[derive(Component)]
 pub struct RadarCamera;
 
-pub fn spawn_radar_cam(
-    mut meshes: ResMut<Assets<Mesh>>,
-    materials: &mut Assets<StandardMaterial>,
-    commands: &mut Commands,
-    mut images: ResMut<Assets<Image>>,
-    pivot: Entity,
-    frame_buffer: Res<stream::FrameBuffer>,
-    config: &Res<config::Config>,
-) -> Handle<Image> {
-    let radar_cam_pos = Vec3::new(config.radar_cam_x_displacement, 1.3, 0.0);
+// A fixed fov camera from the cameras list in the config.
+#[derive(Component)]
+pub struct SensorCamera {
+    pub name: String,
+}
+
+// Copy of a scene mesh drawn only by a special camera, such as the thermal camera.
+#[derive(Component)]
+pub struct Twin;
+
+// Vertical field of view of the radar camera in degrees.
+#[derive(Resource)]
+pub struct Zoom {
+    pub current_fov: f32,
+    pub target_fov: f32,
+    pub min_fov: f32,
+    pub max_fov: f32,
+    pub speed: f32,
+}
+
+impl Zoom {
+    pub fn new(config: &config::Config) -> Self {
+        // Checked to be within the limits when loading the config
+        let fov = config.radar_cam_vertical_fov;
+        Self {
+            current_fov: fov,
+            target_fov: fov,
+            min_fov: config.radar_cam_min_fov,
+            max_fov: config.radar_cam_max_fov,
+            speed: config.radar_cam_zoom_speed,
+        }
+    }
+}
+
+pub fn render_target(images: &mut Assets<Image>, width: u32, height: u32) -> Handle<Image> {
     let size = Extent3d {
-        width: frame_buffer.width,
-        height: frame_buffer.height,
+        width,
+        height,
         depth_or_array_layers: 1,
-        ..default()
     };
     let mut image = Image::new_fill(
         size,
@@ -30,26 +54,47 @@ pub fn spawn_radar_cam(
         TextureFormat::Bgra8UnormSrgb,
         RenderAssetUsages::default(),
     );
-    image.texture_descriptor.usage =
-        TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST | TextureUsages::RENDER_ATTACHMENT;
-    let image_handle = images.add(image);
-    commands.entity(pivot).with_child((
-        Transform::from_xyz(radar_cam_pos.x, radar_cam_pos.y, radar_cam_pos.z),
-        Camera3d::default(),
-        Camera {
-            target: image_handle.clone().into(),
-            order: 1,
-            ..default()
-        },
-        Projection::Perspective(PerspectiveProjection {
-            fov: config.radar_cam_vertical_fov.to_radians(),
-            aspect_ratio: frame_buffer.width as f32 / frame_buffer.height as f32,
-            near: 0.1,
-            far: 1000.0,
-        }),
-        RadarCamera,
-        Visibility::Visible,
-    ));
+    // COPY_SRC lets the streams read the frames back.
+    image.texture_descriptor.usage = TextureUsages::TEXTURE_BINDING
+        | TextureUsages::COPY_SRC
+        | TextureUsages::COPY_DST
+        | TextureUsages::RENDER_ATTACHMENT;
+    images.add(image)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_radar_cam(
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    commands: &mut Commands,
+    images: &mut Assets<Image>,
+    pivot: Entity,
+    frame_buffer: &stream::FrameBuffer,
+    zoom: &Zoom,
+    config: &Res<config::Config>,
+) -> (Handle<Image>, Entity) {
+    let radar_cam_pos = Vec3::new(config.radar_cam_x_displacement, 1.3, 0.0);
+    let image_handle = render_target(images, frame_buffer.width, frame_buffer.height);
+    let camera = commands
+        .spawn((
+            Transform::from_xyz(radar_cam_pos.x, radar_cam_pos.y, radar_cam_pos.z),
+            Camera3d::default(),
+            Camera {
+                target: image_handle.clone().into(),
+                order: 1,
+                ..default()
+            },
+            Projection::Perspective(PerspectiveProjection {
+                fov: zoom.current_fov.to_radians(),
+                aspect_ratio: frame_buffer.width as f32 / frame_buffer.height as f32,
+                near: 0.1,
+                far: 1000.0,
+            }),
+            RadarCamera,
+            Visibility::Visible,
+        ))
+        .id();
+    commands.entity(pivot).add_child(camera);
     let radar_screen = meshes.add(Plane3d {
         normal: Dir3::Z,
         half_size: Vec2::new(0.4, 0.2),
@@ -66,5 +111,60 @@ pub fn spawn_radar_cam(
         MeshMaterial3d(material_handle),
         Transform::from_xyz(0.0, 0.3, 0.57).with_rotation(Quat::from_rotation_x(-0.19)),
     ));
-    image_handle
+    (image_handle, camera)
+}
+
+pub fn spawn_sensor_cam(
+    commands: &mut Commands,
+    images: &mut Assets<Image>,
+    pivot: Entity,
+    config: &config::CameraConfig,
+) -> (Handle<Image>, Entity) {
+    let image_handle = render_target(images, config.width, config.height);
+    let camera = commands
+        .spawn((
+            Transform::from_translation(Vec3::from_array(config.mount_offset)),
+            Camera3d::default(),
+            Camera {
+                target: image_handle.clone().into(),
+                order: 1,
+                ..default()
+            },
+            Projection::Perspective(PerspectiveProjection {
+                fov: config.vertical_fov.to_radians(),
+                aspect_ratio: config.width as f32 / config.height as f32,
+                near: 0.1,
+                far: 1000.0,
+            }),
+            SensorCamera {
+                name: config.name.clone(),
+            },
+            Visibility::Visible,
+        ))
+        .id();
+    commands.entity(pivot).add_child(camera);
+    (image_handle, camera)
+}
+
+pub fn update_zoom(
+    mut zoom: ResMut<Zoom>,
+    time: Res<Time>,
+    mut query: Query<&mut Projection, With<RadarCamera>>,
+) {
+    if zoom.current_fov == zoom.target_fov {
+        return;
+    }
+    let step = zoom.speed * time.delta_secs();
+    let delta = zoom.target_fov - zoom.current_fov;
+    zoom.current_fov = if delta.abs() <= step {
+        zoom.target_fov
+    } else {
+        zoom.current_fov + step * delta.signum()
+    };
+
+    for mut projection in query.iter_mut() {
+        if let Projection::Perspective(perspective) = projection.as_mut() {
+            perspective.fov = zoom.current_fov.to_radians();
+        }
+    }
 }
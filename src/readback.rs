@@ -0,0 +1,259 @@
+use bevy::image::TextureFormatPixelInfo;
+use bevy::prelude::*;
+use bevy::render::render_asset::RenderAssets;
+use bevy::render::render_graph::{
+    Node, NodeRunError, RenderGraph, RenderGraphContext, RenderLabel,
+};
+use bevy::render::render_resource::{
+    Buffer, BufferDescriptor, BufferUsages, Extent3d, MapMode, PollType, TexelCopyBufferInfo,
+    TexelCopyBufferLayout, Texture,
+};
+use bevy::render::renderer::{render_system, RenderContext, RenderDevice};
+use bevy::render::texture::GpuImage;
+use bevy::render::{ExtractSchedule, MainWorld, Render, RenderApp, RenderSystems};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Staging buffers per render target, one is copied into while the other is read.
+const STAGING_BUFFERS: usize = 2;
+
+// Copies render targets to the CPU from a node in the render graph. Each request is
+// copied into a free staging buffer of its target after the cameras have rendered, the
+// buffer is mapped once the GPU is done and a worker thread strips the row padding and
+// hands the pixels to the request, so the app never waits for the GPU unless a request
+// asks to.
+pub struct ReadbackPlugin;
+
+// A copy of image wanted at the end of this frame.
+pub struct ReadbackRequest {
+    // Owner of the staging buffers, usually the stream
+    pub target: Entity,
+    pub image: Handle<Image>,
+    // Called on the worker thread with the tightly packed pixels, or with none when the
+    // image isn't ready or all staging buffers of the target are still in use.
+    pub on_complete: Box<dyn FnOnce(Vec<u8>) + Send + Sync>,
+    // Wait for a staging buffer to come free instead of giving up on the frame
+    pub wait: bool,
+}
+
+// Requests made in the main world since the last extract.
+#[derive(Resource, Default)]
+pub struct ReadbackQueue {
+    requests: Vec<ReadbackRequest>,
+}
+
+impl ReadbackQueue {
+    pub fn request(&mut self, request: ReadbackRequest) {
+        self.requests.push(request);
+    }
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+struct ReadbackLabel;
+
+#[derive(Resource, Default)]
+struct PendingReadbacks(Vec<ReadbackRequest>);
+
+struct StagingBuffer {
+    buffer: Buffer,
+    free: Arc<AtomicBool>,
+}
+
+#[derive(Resource, Default)]
+struct StagingBuffers(HashMap<Entity, Vec<StagingBuffer>>);
+
+// A request with the texture to copy and the staging buffer to copy it into.
+struct PreparedReadback {
+    texture: Texture,
+    size: Extent3d,
+    layout: TexelCopyBufferLayout,
+    buffer: Buffer,
+    free: Arc<AtomicBool>,
+    row_bytes: usize,
+    on_complete: Box<dyn FnOnce(Vec<u8>) + Send + Sync>,
+}
+
+#[derive(Resource, Default)]
+struct PreparedReadbacks(Vec<PreparedReadback>);
+
+// Mapped staging buffers go to the worker thread through this.
+#[derive(Resource)]
+struct MappedSender(Sender<PreparedReadback>);
+
+impl Plugin for ReadbackPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReadbackQueue>();
+
+        let (mapped_tx, mapped_rx) = mpsc::channel();
+        thread::spawn(move || run_unpacker(mapped_rx));
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .init_resource::<PendingReadbacks>()
+            .init_resource::<StagingBuffers>()
+            .init_resource::<PreparedReadbacks>()
+            .insert_resource(MappedSender(mapped_tx))
+            .add_systems(ExtractSchedule, extract_requests)
+            .add_systems(
+                Render,
+                (
+                    prepare_readbacks.in_set(RenderSystems::PrepareResources),
+                    map_readbacks
+                        .after(render_system)
+                        .in_set(RenderSystems::Render),
+                ),
+            );
+        let mut graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        graph.add_node(ReadbackLabel, ReadbackNode);
+        graph.add_node_edge(bevy::render::graph::CameraDriverLabel, ReadbackLabel);
+    }
+}
+
+fn extract_requests(mut main_world: ResMut<MainWorld>, mut pending: ResMut<PendingReadbacks>) {
+    let mut queue = main_world.resource_mut::<ReadbackQueue>();
+    pending.0.append(&mut queue.requests);
+}
+
+fn prepare_readbacks(
+    render_device: Res<RenderDevice>,
+    gpu_images: Res<RenderAssets<GpuImage>>,
+    mut pending: ResMut<PendingReadbacks>,
+    mut staging: ResMut<StagingBuffers>,
+    mut prepared: ResMut<PreparedReadbacks>,
+) {
+    for request in pending.0.drain(..) {
+        let Some(gpu_image) = gpu_images.get(&request.image) else {
+            (request.on_complete)(Vec::new());
+            continue;
+        };
+        let Ok(pixel_size) = gpu_image.texture_format.pixel_size() else {
+            (request.on_complete)(Vec::new());
+            continue;
+        };
+        let size = gpu_image.size;
+        let row_bytes = size.width as usize * pixel_size;
+        let padded_row_bytes = RenderDevice::align_copy_bytes_per_row(row_bytes);
+        let buffer_size = (padded_row_bytes * size.height as usize) as u64;
+
+        let ring = staging.0.entry(request.target).or_default();
+        // The target was resized, its old buffers are dropped once they are unmapped.
+        ring.retain(|staging| staging.buffer.size() == buffer_size);
+        let take_free = |ring: &Vec<StagingBuffer>| {
+            ring.iter()
+                .find(|staging| staging.free.swap(false, Ordering::AcqRel))
+                .map(|staging| (staging.buffer.clone(), staging.free.clone()))
+        };
+        let mut free = take_free(ring);
+        // Let the GPU finish the earlier copies until the worker hands a buffer back.
+        while request.wait && free.is_none() && ring.len() >= STAGING_BUFFERS {
+            let _ = render_device.poll(PollType::Wait);
+            thread::sleep(Duration::from_millis(1));
+            free = take_free(ring);
+        }
+        let (buffer, free) = match free {
+            Some(free) => free,
+            None if ring.len() < STAGING_BUFFERS => {
+                let buffer = render_device.create_buffer(&BufferDescriptor {
+                    label: Some("stream_staging_buffer"),
+                    size: buffer_size,
+                    usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+                    mapped_at_creation: false,
+                });
+                let free = Arc::new(AtomicBool::new(false));
+                ring.push(StagingBuffer {
+                    buffer: buffer.clone(),
+                    free: free.clone(),
+                });
+                (buffer, free)
+            }
+            // Both buffers are still waiting on the GPU or the worker, skip this frame.
+            None => {
+                (request.on_complete)(Vec::new());
+                continue;
+            }
+        };
+
+        prepared.0.push(PreparedReadback {
+            texture: gpu_image.texture.clone(),
+            size,
+            layout: TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_row_bytes as u32),
+                rows_per_image: None,
+            },
+            buffer,
+            free,
+            row_bytes,
+            on_complete: request.on_complete,
+        });
+    }
+}
+
+struct ReadbackNode;
+
+impl Node for ReadbackNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let prepared = world.resource::<PreparedReadbacks>();
+        let encoder = render_context.command_encoder();
+        for readback in &prepared.0 {
+            encoder.copy_texture_to_buffer(
+                readback.texture.as_image_copy(),
+                TexelCopyBufferInfo {
+                    buffer: &readback.buffer,
+                    layout: readback.layout,
+                },
+                readback.size,
+            );
+        }
+        Ok(())
+    }
+}
+
+// Maps the staging buffers once the copies have been submitted.
+fn map_readbacks(mut prepared: ResMut<PreparedReadbacks>, mapped_tx: Res<MappedSender>) {
+    for readback in prepared.0.drain(..) {
+        let buffer = readback.buffer.clone();
+        let mapped_tx = mapped_tx.0.clone();
+        buffer
+            .slice(..)
+            .map_async(MapMode::Read, move |result| match result {
+                Ok(()) => {
+                    let _ = mapped_tx.send(readback);
+                }
+                Err(e) => {
+                    eprintln!("Failed to map stream staging buffer: {}", e);
+                    readback.free.store(true, Ordering::Release);
+                    (readback.on_complete)(Vec::new());
+                }
+            });
+    }
+}
+
+// Copies the rows out of mapped staging buffers and frees them for the next frame.
+fn run_unpacker(mapped_rx: Receiver<PreparedReadback>) {
+    while let Ok(readback) = mapped_rx.recv() {
+        let height = readback.size.height as usize;
+        let mut pixels = Vec::with_capacity(readback.row_bytes * height);
+        {
+            let mapped = readback.buffer.slice(..).get_mapped_range();
+            let padded_row_bytes = mapped.len() / height.max(1);
+            for row in mapped.chunks_exact(padded_row_bytes) {
+                pixels.extend_from_slice(&row[..readback.row_bytes]);
+            }
+        }
+        readback.buffer.unmap();
+        readback.free.store(true, Ordering::Release);
+        (readback.on_complete)(pixels);
+    }
+}
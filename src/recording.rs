@@ -0,0 +1,341 @@
+use crate::config;
+use crate::radar;
+use crate::radar_cam;
+use crate::simulation;
+use crate::stream;
+use bevy::prelude::*;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Records the radar camera video and the pedestal telemetry of each of its frames,
+// started and stopped with the RECORD command or the UI.
+#[derive(Resource)]
+pub struct Recorder {
+    config: config::RecordingConfig,
+    stream: config::StreamConfig,
+    width: u32,
+    height: u32,
+    // Frames the radar camera stream wrote to its encoder
+    frames_rx: Option<Mutex<Receiver<stream::WrittenFrame>>>,
+    // Telemetry of the latest captured frames, waiting for them to be written
+    poses: VecDeque<TelemetryRow>,
+    active: Option<Recording>,
+}
+
+// Frames between capture and the writer that telemetry is kept for.
+const MAX_POSES: usize = 64;
+// Frames waiting for the recording ffmpeg before new ones are dropped.
+const RECORDING_QUEUE: usize = 8;
+
+struct Recording {
+    ffmpeg: Child,
+    frame_tx: SyncSender<(Arc<Vec<u8>>, TelemetryRow)>,
+    // Returns the number of frames recorded
+    writer: JoinHandle<u64>,
+    video_path: PathBuf,
+}
+
+#[derive(Serialize, Clone)]
+struct TelemetryRow {
+    frame: u64,
+    stream_frame: u64,
+    // Seconds since the start of the video
+    video_time: f64,
+    // Unix time in seconds of the capture
+    time: f64,
+    azimuth: f32,
+    elevation: f32,
+    azimuth_velocity: f32,
+    elevation_velocity: f32,
+    target_azimuth: f32,
+    target_elevation: f32,
+    horizontal_fov: f32,
+    vertical_fov: f32,
+}
+
+const CSV_HEADER: &str = "frame,stream_frame,video_time,time,azimuth,elevation,azimuth_velocity,elevation_velocity,target_azimuth,target_elevation,horizontal_fov,vertical_fov";
+
+impl Recorder {
+    pub fn new(config: &config::Config) -> Self {
+        Self {
+            config: config.recording.clone(),
+            stream: config.stream.clone(),
+            width: config.radar_cam_render_width,
+            height: config.radar_cam_render_height,
+            frames_rx: None,
+            poses: VecDeque::new(),
+            active: None,
+        }
+    }
+
+    pub fn video_path(&self) -> Option<&PathBuf> {
+        self.active.as_ref().map(|recording| &recording.video_path)
+    }
+
+    // Starts ffmpeg writing to a new file in the recording directory, named after the
+    // current time, with the telemetry next to it.
+    pub fn start(&mut self) -> Result<PathBuf, String> {
+        if let Some(path) = self.video_path() {
+            return Err(format!("already recording to {}", path.display()));
+        }
+        let directory = PathBuf::from(&self.config.directory);
+        fs::create_dir_all(&directory).map_err(|e| e.to_string())?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let name = format!("recording_{}", millis);
+        let video_path = directory.join(format!(
+            "{}.{}",
+            name,
+            match self.config.container {
+                config::Container::Mp4 => "mp4",
+                config::Container::Mkv => "mkv",
+            }
+        ));
+        let telemetry_path = directory.join(format!(
+            "{}.{}",
+            name,
+            match self.config.telemetry {
+                config::TelemetryFormat::Csv => "csv",
+                config::TelemetryFormat::Jsonl => "jsonl",
+            }
+        ));
+
+        // Never overwrite an earlier recording.
+        let telemetry_file = File::create_new(&telemetry_path)
+            .map_err(|e| format!("{}: {}", telemetry_path.display(), e))?;
+        let mut telemetry = BufWriter::new(telemetry_file);
+        if self.config.telemetry == config::TelemetryFormat::Csv {
+            writeln!(telemetry, "{}", CSV_HEADER).map_err(|e| e.to_string())?;
+        }
+
+        let size = format!("{}x{}", self.width, self.height);
+        let rate = self.stream.frame_rate.to_string();
+        let mut ffmpeg = Command::new(stream::ffmpeg_path())
+            .args([
+                "-f",
+                "rawvideo",
+                "-video_size",
+                &size,
+                "-framerate",
+                &rate,
+                "-pixel_format",
+                "bgra",
+                "-i",
+                "-",
+            ])
+            .args(stream::codec_args(&self.stream, self.stream.frame_rate))
+            .arg("-n")
+            .arg(&video_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("failed to start FFmpeg: {}", e))?;
+        let stdin = ffmpeg.stdin.take().expect("Failed to capture FFmpeg stdin");
+        stream::log_output("Recording FFmpeg", ffmpeg.stderr.take());
+        let (frame_tx, frame_rx) = mpsc::sync_channel(RECORDING_QUEUE);
+        let format = self.config.telemetry;
+        let frame_rate = self.stream.frame_rate as f64;
+        let writer =
+            thread::spawn(move || run_writer(frame_rx, stdin, telemetry, format, frame_rate));
+
+        println!(
+            "Recording to {} with telemetry in {}",
+            video_path.display(),
+            telemetry_path.display()
+        );
+        self.active = Some(Recording {
+            ffmpeg,
+            frame_tx,
+            writer,
+            video_path: video_path.clone(),
+        });
+        Ok(video_path)
+    }
+
+    // Closes the files, ffmpeg finishes the video in the background unless wait is set.
+    pub fn stop(&mut self, wait: bool) -> Option<PathBuf> {
+        let Recording {
+            mut ffmpeg,
+            frame_tx,
+            writer,
+            video_path,
+        } = self.active.take()?;
+        // The writer closes stdin once the queued frames are written, which ends the input
+        // so ffmpeg writes the trailer.
+        drop(frame_tx);
+        let path = video_path.clone();
+        let finish = move || {
+            let frames = writer.join().unwrap_or(0);
+            let _ = ffmpeg.wait();
+            println!(
+                "Stopped recording {} after {} frames",
+                path.display(),
+                frames
+            );
+        };
+        if wait {
+            finish();
+        } else {
+            thread::spawn(finish);
+        }
+        Some(video_path)
+    }
+}
+
+// Appends every frame the radar camera stream writes to the recording, with the
+// telemetry from the tick it was captured on.
+pub fn record_frames(
+    mut recorder: ResMut<Recorder>,
+    streams: Query<&stream::CameraStream>,
+    radar_camera: Query<(Entity, &Projection), With<radar_cam::RadarCamera>>,
+    radar: Res<radar::Radar>,
+    time: Res<Time>,
+    simulation: Res<simulation::Simulation>,
+) {
+    let Ok((camera, projection)) = radar_camera.single() else {
+        return;
+    };
+    let Some(camera_stream) = streams.iter().find(|s| s.camera == camera) else {
+        return;
+    };
+    if recorder.frames_rx.is_none() {
+        recorder.frames_rx = Some(Mutex::new(camera_stream.subscribe()));
+    }
+
+    let frame_number = camera_stream.frame_number;
+    if recorder
+        .poses
+        .back()
+        .is_none_or(|pose| pose.stream_frame != frame_number)
+    {
+        let vertical_fov = match projection {
+            Projection::Perspective(perspective) => perspective.fov,
+            _ => 0.0,
+        };
+        let frame_buffer = &camera_stream.frame_buffer;
+        let aspect_ratio = frame_buffer.width as f32 / frame_buffer.height as f32;
+        let horizontal_fov = 2.0 * ((vertical_fov / 2.0).tan() * aspect_ratio).atan();
+        recorder.poses.push_back(TelemetryRow {
+            frame: 0,
+            stream_frame: frame_number,
+            video_time: 0.0,
+            time: simulation.unix_time_us(time.elapsed()) as f64 / 1e6,
+            azimuth: radar.current.azimuth,
+            elevation: radar.current.elevation,
+            azimuth_velocity: radar.azimuth_velocity,
+            elevation_velocity: radar.elevation_velocity,
+            target_azimuth: radar.target.azimuth,
+            target_elevation: radar.target.elevation,
+            horizontal_fov: horizontal_fov.to_degrees(),
+            vertical_fov: vertical_fov.to_degrees(),
+        });
+        if recorder.poses.len() > MAX_POSES {
+            recorder.poses.pop_front();
+        }
+    }
+
+    let written: Vec<_> = recorder
+        .frames_rx
+        .as_ref()
+        .map(|rx| rx.lock().unwrap().try_iter().collect())
+        .unwrap_or_default();
+    for frame in written {
+        let Some(row) = recorder
+            .poses
+            .iter()
+            .find(|pose| pose.stream_frame == frame.number)
+            .cloned()
+        else {
+            continue;
+        };
+        let Some(recording) = recorder.active.as_ref() else {
+            continue;
+        };
+        match recording.frame_tx.try_send((frame.data, row)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                eprintln!("Recording fell behind, dropped frame {}", frame.number);
+            }
+            // The writer gave up on ffmpeg.
+            Err(TrySendError::Disconnected(_)) => {
+                recorder.stop(false);
+                return;
+            }
+        }
+    }
+}
+
+// Writes the frames to the recording ffmpeg and their telemetry to the file away from the
+// main thread, until the recording is stopped or ffmpeg goes away.
+fn run_writer(
+    frame_rx: Receiver<(Arc<Vec<u8>>, TelemetryRow)>,
+    mut stdin: ChildStdin,
+    mut telemetry: BufWriter<File>,
+    format: config::TelemetryFormat,
+    frame_rate: f64,
+) -> u64 {
+    let mut frames = 0;
+    while let Ok((data, mut row)) = frame_rx.recv() {
+        if let Err(e) = stdin.write_all(&data) {
+            eprintln!("Failed to write recording frame: {}", e);
+            break;
+        }
+        row.frame = frames;
+        row.video_time = frames as f64 / frame_rate;
+        if let Err(e) = write_row(&mut telemetry, format, &row) {
+            eprintln!("Failed to write recording telemetry: {}", e);
+        }
+        frames += 1;
+    }
+    if let Err(e) = telemetry.flush() {
+        eprintln!("Failed to write recording telemetry: {}", e);
+    }
+    frames
+}
+
+fn write_row(
+    telemetry: &mut BufWriter<File>,
+    format: config::TelemetryFormat,
+    row: &TelemetryRow,
+) -> std::io::Result<()> {
+    match format {
+        config::TelemetryFormat::Csv => writeln!(
+            telemetry,
+            "{},{},{:.4},{:.6},{},{},{},{},{},{},{},{}",
+            row.frame,
+            row.stream_frame,
+            row.video_time,
+            row.time,
+            row.azimuth,
+            row.elevation,
+            row.azimuth_velocity,
+            row.elevation_velocity,
+            row.target_azimuth,
+            row.target_elevation,
+            row.horizontal_fov,
+            row.vertical_fov
+        ),
+        config::TelemetryFormat::Jsonl => {
+            let line = serde_json::to_string(row).map_err(std::io::Error::other)?;
+            writeln!(telemetry, "{}", line)
+        }
+    }
+}
+
+// Finishes the video before the app exits so the file stays playable.
+pub fn stop_on_exit(mut exit: MessageReader<AppExit>, mut recorder: ResMut<Recorder>) {
+    if exit.read().next().is_some() {
+        recorder.stop(true);
+    }
+}
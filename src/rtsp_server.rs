@@ -0,0 +1,594 @@
+use crate::metrics;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::IpAddr;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+// Mounted streams by path, filled in by mount and looked up by the client connections.
+static MOUNTS: Lazy<Mutex<HashMap<String, Arc<Mount>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_SESSION: AtomicU32 = AtomicU32::new(1);
+// Playing clients across all mounts, read by the metrics without locking the mounts.
+static CONNECTED_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+const PAYLOAD_TYPE: u8 = 96;
+// Largest RTP payload, keeps the packets below a typical MTU.
+const MAX_PAYLOAD: usize = 1400;
+// Access units queued for a player before it is considered too slow and skips ahead.
+const PLAYER_QUEUE: usize = 8;
+
+// An H.264 stream clients can play at rtsp://<host>:<port>/<path>.
+pub struct Mount {
+    start: Instant,
+    sps: Mutex<Option<Vec<u8>>>,
+    pps: Mutex<Option<Vec<u8>>>,
+    players: Mutex<Vec<Player>>,
+}
+
+// A playing client, fed by its own sender thread so a slow one holds up nobody else.
+struct Player {
+    session: u32,
+    tx: SyncSender<Arc<AccessUnit>>,
+    // Players start at a keyframe so their decoder has the parameter sets, and start over
+    // at the next one when their queue was full.
+    waiting: bool,
+}
+
+struct AccessUnit {
+    timestamp: u32,
+    nals: Vec<Vec<u8>>,
+}
+
+// The RTP state of a player, owned by its sender thread.
+struct RtpSender {
+    transport: Transport,
+    ssrc: u32,
+    sequence: u16,
+}
+
+#[derive(Clone)]
+enum Transport {
+    // RTP interleaved on the RTSP connection
+    Tcp(Arc<Mutex<TcpStream>>, u8),
+    Udp(Arc<UdpSocket>, SocketAddr),
+}
+
+impl Mount {
+    // Sends one Annex B access unit to every player.
+    pub fn send(&self, access_unit: &[u8]) {
+        let timestamp = (self.start.elapsed().as_secs_f64() * 90_000.0) as u32;
+        let nals: Vec<&[u8]> = split_nals(access_unit)
+            .filter(|nal| !nal.is_empty() && nal[0] & 0x1F != 9)
+            .collect();
+        let mut keyframe = false;
+        let mut has_parameter_sets = false;
+        for nal in &nals {
+            match nal[0] & 0x1F {
+                5 => keyframe = true,
+                7 => {
+                    *self.sps.lock().unwrap() = Some(nal.to_vec());
+                    has_parameter_sets = true;
+                }
+                8 => *self.pps.lock().unwrap() = Some(nal.to_vec()),
+                _ => {}
+            }
+        }
+        let unit = Arc::new(AccessUnit {
+            timestamp,
+            nals: nals.iter().map(|nal| nal.to_vec()).collect(),
+        });
+        let mut with_parameter_sets = None;
+
+        let mut players = self.players.lock().unwrap();
+        players.retain_mut(|player| {
+            let unit = if player.waiting {
+                if !keyframe {
+                    return true;
+                }
+                player.waiting = false;
+                if has_parameter_sets {
+                    unit.clone()
+                } else {
+                    with_parameter_sets
+                        .get_or_insert_with(|| {
+                            let parameter_sets = [&self.sps, &self.pps]
+                                .into_iter()
+                                .filter_map(|p| p.lock().unwrap().clone());
+                            Arc::new(AccessUnit {
+                                timestamp,
+                                nals: parameter_sets.chain(unit.nals.iter().cloned()).collect(),
+                            })
+                        })
+                        .clone()
+                }
+            } else {
+                unit.clone()
+            };
+            match player.tx.try_send(unit) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    player.waiting = true;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    CONNECTED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+                    false
+                }
+            }
+        });
+    }
+
+    fn sdp(&self, path: &str, local: IpAddr) -> String {
+        let mut fmtp = "packetization-mode=1".to_string();
+        if let (Some(sps), Some(pps)) = (&*self.sps.lock().unwrap(), &*self.pps.lock().unwrap()) {
+            if sps.len() >= 4 {
+                fmtp += &format!(
+                    ";profile-level-id={:02X}{:02X}{:02X};sprop-parameter-sets={},{}",
+                    sps[1],
+                    sps[2],
+                    sps[3],
+                    base64(sps),
+                    base64(pps)
+                );
+            }
+        }
+        let family = if local.is_ipv4() { "IP4" } else { "IP6" };
+        format!(
+            "v=0\r\no=- 0 0 IN {} {}\r\ns={}\r\nc=IN {} {}\r\nt=0 0\r\n\
+             m=video 0 RTP/AVP {}\r\na=rtpmap:{} H264/90000\r\na=fmtp:{} {}\r\n\
+             a=control:trackID=0\r\n",
+            family,
+            local,
+            path,
+            family,
+            if local.is_ipv4() { "0.0.0.0" } else { "::" },
+            PAYLOAD_TYPE,
+            PAYLOAD_TYPE,
+            PAYLOAD_TYPE,
+            fmtp
+        )
+    }
+}
+
+impl RtpSender {
+    // Sends the queued access units until the client goes away.
+    fn run(mut self, rx: Receiver<Arc<AccessUnit>>) {
+        while let Ok(unit) = rx.recv() {
+            let last = unit.nals.len();
+            let sent = unit
+                .nals
+                .iter()
+                .enumerate()
+                .all(|(i, nal)| self.send_nal(nal, unit.timestamp, i + 1 == last).is_ok());
+            if !sent {
+                return;
+            }
+        }
+    }
+
+    // Packs a NAL unit in a single RTP packet or splits it in FU-A fragments.
+    fn send_nal(&mut self, nal: &[u8], timestamp: u32, last: bool) -> std::io::Result<()> {
+        if nal.len() <= MAX_PAYLOAD {
+            return self.send_packet(&[], nal, timestamp, last);
+        }
+        let indicator = (nal[0] & 0xE0) | 28;
+        let nal_type = nal[0] & 0x1F;
+        let chunks: Vec<&[u8]> = nal[1..].chunks(MAX_PAYLOAD - 2).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut header = nal_type;
+            if i == 0 {
+                header |= 0x80;
+            }
+            let end = i + 1 == chunks.len();
+            if end {
+                header |= 0x40;
+            }
+            self.send_packet(&[indicator, header], chunk, timestamp, last && end)?;
+        }
+        Ok(())
+    }
+
+    fn send_packet(
+        &mut self,
+        prefix: &[u8],
+        payload: &[u8],
+        timestamp: u32,
+        marker: bool,
+    ) -> std::io::Result<()> {
+        let mut packet = Vec::with_capacity(12 + prefix.len() + payload.len());
+        packet.push(0x80);
+        packet.push(PAYLOAD_TYPE | if marker { 0x80 } else { 0 });
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(prefix);
+        packet.extend_from_slice(payload);
+        self.sequence = self.sequence.wrapping_add(1);
+
+        match &self.transport {
+            Transport::Tcp(stream, channel) => {
+                let mut frame = vec![b'$', *channel];
+                frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+                frame.extend_from_slice(&packet);
+                stream.lock().unwrap().write_all(&frame)
+            }
+            Transport::Udp(socket, address) => socket.send_to(&packet, address).map(|_| ()),
+        }
+    }
+}
+
+// Splits an Annex B byte stream into NAL units without their start codes.
+fn split_nals(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    let ends: Vec<usize> = starts
+        .iter()
+        .skip(1)
+        .map(|&next| {
+            // A four byte start code has an extra leading zero.
+            let mut end = next - 3;
+            if end > 0 && data[end - 1] == 0 {
+                end -= 1;
+            }
+            end
+        })
+        .chain(std::iter::once(data.len()))
+        .collect();
+    starts
+        .into_iter()
+        .zip(ends)
+        .map(move |(start, end)| &data[start..end.max(start)])
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+pub fn mount(path: &str) -> Arc<Mount> {
+    let mount = Arc::new(Mount {
+        start: Instant::now(),
+        sps: Mutex::new(None),
+        pps: Mutex::new(None),
+        players: Mutex::new(Vec::new()),
+    });
+    MOUNTS
+        .lock()
+        .unwrap()
+        .insert(path.to_string(), mount.clone());
+    mount
+}
+
+pub fn url(host: &str, port: u16, path: &str) -> String {
+    format!("rtsp://{}:{}/{}", host, port, path)
+}
+
+// Listens on the host of the stream url, like mediamtx would for remote viewers.
+pub fn start_server(host: &str, port: u16) {
+    metrics::register_clients("rtsp", || CONNECTED_CLIENTS.load(Ordering::Relaxed));
+    let address = format!("{}:{}", host, port);
+    let listener = TcpListener::bind(&address).expect("Could not bind RTSP address");
+    println!("RTSP server running on {}", address);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    thread::spawn(move || {
+                        if let Err(e) = handle_client(stream) {
+                            eprintln!("RTSP client error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept an RTSP connection: {:?}", e),
+            }
+        }
+    });
+}
+
+struct Request {
+    method: String,
+    url: String,
+    headers: HashMap<String, String>,
+}
+
+// Reads the next request, skipping any interleaved RTCP packets from the client.
+fn read_request(reader: &mut BufReader<TcpStream>) -> std::io::Result<Option<Request>> {
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(None);
+        }
+        if buffer[0] != b'$' {
+            break;
+        }
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header)?;
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        reader.read_exact(&mut vec![0u8; length])?;
+    }
+
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let url = parts.next().unwrap_or_default().to_string();
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    if let Some(length) = headers.get("content-length").and_then(|l| l.parse().ok()) {
+        reader.read_exact(&mut vec![0u8; length])?;
+    }
+    Ok(Some(Request {
+        method,
+        url,
+        headers,
+    }))
+}
+
+// Path of the mount in rtsp://host:port/<path>[/trackID=0]
+fn mount_path(url: &str) -> String {
+    let path = url
+        .split("://")
+        .nth(1)
+        .and_then(|rest| rest.split_once('/'))
+        .map(|(_, path)| path)
+        .unwrap_or_default();
+    path.trim_end_matches('/')
+        .trim_end_matches("trackID=0")
+        .trim_end_matches('/')
+        .to_string()
+}
+
+fn handle_client(stream: TcpStream) -> std::io::Result<()> {
+    let peer = stream.peer_addr()?;
+    let local = stream.local_addr()?.ip();
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut reader = BufReader::new(stream);
+    let session = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+    // The stream and transport chosen by SETUP, started by PLAY
+    let mut setup: Option<(Arc<Mount>, Transport)> = None;
+
+    while let Some(request) = read_request(&mut reader)? {
+        let cseq = request.headers.get("cseq").cloned().unwrap_or_default();
+        let mut status = "200 OK";
+        let mut headers = vec![];
+        let mut body = String::new();
+
+        let path = mount_path(&request.url);
+        let mount = MOUNTS.lock().unwrap().get(&path).cloned();
+        match (request.method.as_str(), mount) {
+            ("OPTIONS", _) => headers.push(
+                "Public: OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER".to_string(),
+            ),
+            ("GET_PARAMETER", _) => {}
+            ("DESCRIBE", Some(mount)) => {
+                body = mount.sdp(&path, local);
+                headers.push(format!(
+                    "Content-Base: {}/",
+                    request.url.trim_end_matches('/')
+                ));
+                headers.push("Content-Type: application/sdp".to_string());
+            }
+            ("SETUP", Some(mount)) => {
+                let requested = request
+                    .headers
+                    .get("transport")
+                    .cloned()
+                    .unwrap_or_default();
+                match setup_transport(&requested, &writer, local, peer) {
+                    Ok((transport, reply)) => {
+                        headers.push(format!("Transport: {}", reply));
+                        setup = Some((mount, transport));
+                    }
+                    Err(e) => {
+                        eprintln!("RTSP transport {} not supported: {}", requested, e);
+                        status = "461 Unsupported Transport";
+                    }
+                }
+            }
+            ("PLAY", _) => match &setup {
+                Some((mount, transport)) => {
+                    println!("RTSP client {} playing {}", peer, path);
+                    headers.push("Range: npt=0.000-".to_string());
+                    remove_player(&setup, session);
+                    let (tx, rx) = mpsc::sync_channel(PLAYER_QUEUE);
+                    let sender = RtpSender {
+                        transport: transport.clone(),
+                        ssrc: session.wrapping_mul(0x9E37_79B9),
+                        sequence: 0,
+                    };
+                    thread::spawn(move || sender.run(rx));
+                    mount.players.lock().unwrap().push(Player {
+                        session,
+                        tx,
+                        waiting: true,
+                    });
+                    CONNECTED_CLIENTS.fetch_add(1, Ordering::Relaxed);
+                }
+                None => status = "455 Method Not Valid in This State",
+            },
+            ("TEARDOWN", _) => {
+                remove_player(&setup, session);
+                setup = None;
+            }
+            ("DESCRIBE" | "SETUP", None) => status = "404 Not Found",
+            _ => status = "501 Not Implemented",
+        }
+
+        if matches!(request.method.as_str(), "SETUP" | "PLAY" | "GET_PARAMETER") {
+            headers.push(format!("Session: {};timeout=60", session));
+        }
+        let mut response = format!("RTSP/1.0 {}\r\nCSeq: {}\r\n", status, cseq);
+        for header in headers {
+            response += &header;
+            response += "\r\n";
+        }
+        response += &format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        writer.lock().unwrap().write_all(response.as_bytes())?;
+    }
+    remove_player(&setup, session);
+    Ok(())
+}
+
+fn setup_transport(
+    requested: &str,
+    writer: &Arc<Mutex<TcpStream>>,
+    local: IpAddr,
+    peer: SocketAddr,
+) -> std::io::Result<(Transport, String)> {
+    let field = |name: &str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strip_prefix(name))
+            .and_then(|ports| ports.split('-').next())
+            .and_then(|port| port.parse::<u16>().ok())
+    };
+    // RTCP goes on the channel or port after the RTP one, which has to exist.
+    let pair = |first: u16| Some((first, first.checked_add(1)?));
+    if requested.contains("RTP/AVP/TCP") {
+        let channel = u8::try_from(field("interleaved=").unwrap_or(0)).ok();
+        let Some((channel, rtcp_channel)) = channel.and_then(|c| Some((c, c.checked_add(1)?)))
+        else {
+            return Err(std::io::Error::other("interleaved channel out of range"));
+        };
+        let reply = format!(
+            "RTP/AVP/TCP;unicast;interleaved={}-{}",
+            channel, rtcp_channel
+        );
+        return Ok((Transport::Tcp(writer.clone(), channel), reply));
+    }
+    let Some((client_port, client_rtcp_port)) = field("client_port=").and_then(pair) else {
+        return Err(std::io::Error::other("no client_port pair"));
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0))?;
+    let Some((server_port, server_rtcp_port)) = pair(socket.local_addr()?.port()) else {
+        return Err(std::io::Error::other("no server_port pair"));
+    };
+    let reply = format!(
+        "RTP/AVP;unicast;client_port={}-{};server_port={}-{}",
+        client_port, client_rtcp_port, server_port, server_rtcp_port
+    );
+    let address = SocketAddr::new(peer.ip(), client_port);
+    Ok((Transport::Udp(Arc::new(socket), address), reply))
+}
+
+fn remove_player(setup: &Option<(Arc<Mount>, Transport)>, session: u32) {
+    if let Some((mount, _)) = setup {
+        mount.players.lock().unwrap().retain(|player| {
+            let removed = player.session == session;
+            if removed {
+                CONNECTED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+            }
+            !removed
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nals(data: &[u8]) -> Vec<Vec<u8>> {
+        split_nals(data).map(|nal| nal.to_vec()).collect()
+    }
+
+    #[test]
+    fn split_nals_handles_three_and_four_byte_start_codes() {
+        let data = [
+            0, 0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x68, 3, 0, 0, 0, 1, 0x65, 4, 5,
+        ];
+        assert_eq!(
+            nals(&data),
+            vec![vec![0x67, 1, 2], vec![0x68, 3], vec![0x65, 4, 5]]
+        );
+    }
+
+    #[test]
+    fn split_nals_skips_bytes_before_the_first_start_code() {
+        assert_eq!(nals(&[9, 9, 0, 0, 1, 0x09, 0xF0]), vec![vec![0x09, 0xF0]]);
+        assert!(nals(&[]).is_empty());
+        assert!(nals(&[0, 0]).is_empty());
+    }
+
+    #[test]
+    fn base64_matches_rfc_4648() {
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (data, encoded) in cases {
+            assert_eq!(base64(data.as_bytes()), encoded);
+        }
+        assert_eq!(base64(&[0xFF, 0xEF]), "/+8=");
+    }
+
+    fn transport(requested: &str) -> std::io::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let writer = Arc::new(Mutex::new(TcpStream::connect(listener.local_addr()?)?));
+        let local = IpAddr::from([127, 0, 0, 1]);
+        let peer = SocketAddr::new(local, 6000);
+        setup_transport(requested, &writer, local, peer).map(|(_, reply)| reply)
+    }
+
+    #[test]
+    fn transport_pairs_rtp_with_rtcp() {
+        assert_eq!(
+            transport("RTP/AVP/TCP;unicast;interleaved=2-3").unwrap(),
+            "RTP/AVP/TCP;unicast;interleaved=2-3"
+        );
+        assert!(transport("RTP/AVP;unicast;client_port=5000-5001")
+            .unwrap()
+            .starts_with("RTP/AVP;unicast;client_port=5000-5001;server_port="));
+    }
+
+    #[test]
+    fn transport_rejects_channels_and_ports_without_a_successor() {
+        assert!(transport("RTP/AVP/TCP;unicast;interleaved=255").is_err());
+        assert!(transport("RTP/AVP/TCP;unicast;interleaved=300-301").is_err());
+        assert!(transport("RTP/AVP;unicast;client_port=65535").is_err());
+        assert!(transport("RTP/AVP;unicast").is_err());
+    }
+}
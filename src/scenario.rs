@@ -0,0 +1,276 @@
+use crate::labels;
+use crate::thermal::Temperature;
+use bevy::prelude::*;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::{error::Error, fs};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Scenario {
+    #[serde(default)]
+    pub targets: Vec<TargetSpec>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PathMode {
+    #[default]
+    Once,
+    Loop,
+    PingPong,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Shape {
+    #[default]
+    Cuboid,
+    Sphere,
+    Cylinder,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Waypoint {
+    pub position: [f32; 3],
+    // Speed used on the leg leading to this waypoint, overrides the target speed.
+    pub speed: Option<f32>,
+    // Seconds to hold position once the waypoint is reached.
+    #[serde(default)]
+    pub wait: f32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TargetSpec {
+    pub name: String,
+    #[serde(default = "default_class")]
+    pub class: String,
+    // glTF model relative to the assets directory. A primitive shape is used if not set.
+    pub model: Option<String>,
+    #[serde(default)]
+    pub shape: Shape,
+    #[serde(default = "default_size")]
+    pub size: [f32; 3],
+    #[serde(default = "default_color")]
+    pub color: [f32; 3],
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    // Degrees Celsius seen by the thermal camera
+    pub temperature: Option<f32>,
+    pub start: [f32; 3],
+    #[serde(default)]
+    pub start_time: f32,
+    #[serde(default)]
+    pub speed: f32,
+    #[serde(default)]
+    pub path: PathMode,
+    #[serde(default)]
+    pub waypoints: Vec<Waypoint>,
+}
+
+fn default_class() -> String {
+    "target".to_string()
+}
+
+fn default_size() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+fn default_color() -> [f32; 3] {
+    [0.8, 0.8, 0.8]
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+impl Scenario {
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn Error>> {
+        let scenario_str = fs::read_to_string(path)?;
+        let scenario: Scenario = toml::from_str(&scenario_str)?;
+        // Every target and class needs its own id in the segmentation labels.
+        let classes: BTreeSet<_> = scenario.targets.iter().map(|spec| &spec.class).collect();
+        if classes.len() > labels::MAX_CLASSES || scenario.targets.len() > labels::MAX_INSTANCES {
+            return Err(format!(
+                "a scenario holds at most {} classes and {} targets",
+                labels::MAX_CLASSES,
+                labels::MAX_INSTANCES
+            )
+            .into());
+        }
+        for spec in &scenario.targets {
+            if spec.waypoints.is_empty() {
+                continue;
+            }
+            let mut speeds: Vec<f32> = spec
+                .waypoints
+                .iter()
+                .map(|waypoint| waypoint.speed.unwrap_or(spec.speed))
+                .collect();
+            // The leg back to the start uses the target speed.
+            if spec.path != PathMode::Once {
+                speeds.push(spec.speed);
+            }
+            if !speeds.iter().all(|speed| speed.is_finite() && *speed > 0.0) {
+                return Err(
+                    format!("target {} needs a speed above 0 on every leg", spec.name).into(),
+                );
+            }
+        }
+        Ok(scenario)
+    }
+}
+
+#[derive(Component, Debug, Clone)]
+pub struct Target {
+    pub name: String,
+    pub class: String,
+}
+
+#[derive(Component, Debug)]
+pub struct TargetMotion {
+    points: Vec<Vec3>,
+    speeds: Vec<f32>,
+    waits: Vec<f32>,
+    mode: PathMode,
+    start_time: f32,
+    elapsed: f32,
+    wait_remaining: f32,
+    next: usize,
+    forward: bool,
+    finished: bool,
+}
+
+impl TargetMotion {
+    fn new(spec: &TargetSpec) -> Self {
+        // Index 0 is the start position, waypoints follow.
+        let mut points = vec![Vec3::from_array(spec.start)];
+        let mut speeds = vec![spec.speed];
+        let mut waits = vec![0.0];
+        for waypoint in &spec.waypoints {
+            points.push(Vec3::from_array(waypoint.position));
+            speeds.push(waypoint.speed.unwrap_or(spec.speed));
+            waits.push(waypoint.wait);
+        }
+        Self {
+            finished: points.len() < 2,
+            points,
+            speeds,
+            waits,
+            mode: spec.path,
+            start_time: spec.start_time,
+            elapsed: 0.0,
+            wait_remaining: 0.0,
+            next: 1,
+            forward: true,
+        }
+    }
+
+    fn advance_index(&mut self) {
+        let last = self.points.len() - 1;
+        match self.mode {
+            PathMode::Once => {
+                if self.next == last {
+                    self.finished = true;
+                } else {
+                    self.next += 1;
+                }
+            }
+            PathMode::Loop => {
+                self.next = (self.next + 1) % self.points.len();
+            }
+            PathMode::PingPong => {
+                if self.forward && self.next == last {
+                    self.forward = false;
+                } else if !self.forward && self.next == 0 {
+                    self.forward = true;
+                }
+                if self.forward {
+                    self.next += 1;
+                } else {
+                    self.next -= 1;
+                }
+            }
+        }
+    }
+}
+
+pub fn spawn_scenario(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    asset_server: &AssetServer,
+    scenario: &Scenario,
+) {
+    for spec in &scenario.targets {
+        let start = Vec3::from_array(spec.start);
+        let mut transform = Transform::from_translation(start).with_scale(Vec3::splat(spec.scale));
+        if let Some(first) = spec.waypoints.first() {
+            let direction = Vec3::from_array(first.position) - start;
+            if direction.xz().length() > f32::EPSILON {
+                transform.look_to(Vec3::new(direction.x, 0.0, direction.z), Vec3::Y);
+            }
+        }
+        let mut entity = commands.spawn((
+            Target {
+                name: spec.name.clone(),
+                class: spec.class.clone(),
+            },
+            TargetMotion::new(spec),
+            transform,
+            Visibility::Visible,
+        ));
+        if let Some(temperature) = spec.temperature {
+            entity.insert(Temperature(temperature));
+        }
+        if let Some(model) = &spec.model {
+            let scene = asset_server.load(GltfAssetLabel::Scene(0).from_asset(model.clone()));
+            entity.insert(SceneRoot(scene));
+        } else {
+            let size = Vec3::from_array(spec.size);
+            let mesh = match spec.shape {
+                Shape::Cuboid => meshes.add(Cuboid::from_size(size)),
+                Shape::Sphere => meshes.add(Sphere::new(size.max_element() / 2.0)),
+                Shape::Cylinder => meshes.add(Cylinder::new(size.x / 2.0, size.y)),
+            };
+            let [r, g, b] = spec.color;
+            let material = materials.add(Color::linear_rgb(r, g, b));
+            // Primitives are centered, lift them so they rest on their start position.
+            entity.with_child((
+                Mesh3d(mesh),
+                MeshMaterial3d(material),
+                Transform::from_xyz(0.0, size.y / 2.0, 0.0),
+            ));
+        }
+        println!("Spawned target {} ({})", spec.name, spec.class);
+    }
+}
+
+pub fn update_targets(time: Res<Time>, mut query: Query<(&mut Transform, &mut TargetMotion)>) {
+    let ds = time.delta_secs();
+    for (mut transform, mut motion) in query.iter_mut() {
+        motion.elapsed += ds;
+        if motion.finished || motion.elapsed < motion.start_time {
+            continue;
+        }
+        if motion.wait_remaining > 0.0 {
+            motion.wait_remaining -= ds;
+            continue;
+        }
+
+        let target = motion.points[motion.next];
+        let speed = motion.speeds[motion.next];
+        let to_target = target - transform.translation;
+        let step = speed * ds;
+        if to_target.length() <= step {
+            transform.translation = target;
+            motion.wait_remaining = motion.waits[motion.next];
+            motion.advance_index();
+        } else {
+            let direction = to_target.normalize();
+            transform.translation += direction * step;
+            if direction.xz().length() > f32::EPSILON {
+                transform.look_to(Vec3::new(direction.x, 0.0, direction.z), Vec3::Y);
+            }
+        }
+    }
+}
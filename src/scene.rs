@@ -0,0 +1,70 @@
+use crate::thermal::Temperature;
+use bevy::prelude::*;
+use serde::Deserialize;
+use std::{error::Error, fs};
+
+// A test site around the pedestal built from glTF models.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Scene {
+    // glTF file spawned at the origin holding the whole site
+    pub level: Option<String>,
+    // Keeps the generated ground, trees and houses
+    #[serde(default = "default_builtin")]
+    pub builtin: bool,
+    #[serde(default)]
+    pub models: Vec<ModelSpec>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ModelSpec {
+    // glTF model relative to the assets directory
+    pub model: String,
+    // Scene of the glTF file to spawn
+    #[serde(default)]
+    pub scene: usize,
+    pub position: [f32; 3],
+    // Yaw, pitch and roll in degrees
+    #[serde(default)]
+    pub rotation: [f32; 3],
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    // Degrees Celsius seen by the thermal camera
+    pub temperature: Option<f32>,
+}
+
+fn default_builtin() -> bool {
+    true
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+impl Scene {
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn Error>> {
+        let scene_str = fs::read_to_string(path)?;
+        let scene: Scene = toml::from_str(&scene_str)?;
+        Ok(scene)
+    }
+}
+
+pub fn spawn_scene(commands: &mut Commands, asset_server: &AssetServer, scene: &Scene) {
+    if let Some(level) = &scene.level {
+        let handle = asset_server.load(GltfAssetLabel::Scene(0).from_asset(level.clone()));
+        commands.spawn((SceneRoot(handle), Transform::default()));
+        println!("Spawned level {}", level);
+    }
+    for spec in &scene.models {
+        let [yaw, pitch, roll] = spec.rotation.map(f32::to_radians);
+        let transform = Transform::from_translation(Vec3::from_array(spec.position))
+            .with_rotation(Quat::from_euler(EulerRot::YXZ, yaw, pitch, roll))
+            .with_scale(Vec3::splat(spec.scale));
+        let handle =
+            asset_server.load(GltfAssetLabel::Scene(spec.scene).from_asset(spec.model.clone()));
+        let mut entity = commands.spawn((SceneRoot(handle), transform));
+        if let Some(temperature) = spec.temperature {
+            entity.insert(Temperature(temperature));
+        }
+    }
+    println!("Spawned {} scene models", scene.models.len());
+}
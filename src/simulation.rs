@@ -0,0 +1,149 @@
+use crate::config;
+use crate::stream;
+use bevy::prelude::*;
+use bevy::time::TimeUpdateStrategy;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const MIN_SPEED: f32 = 0.1;
+pub const MAX_SPEED: f32 = 10.0;
+
+// Clock of the simulation, which can be paused and run faster or slower than real time.
+// In deterministic mode every app update advances the simulation by exactly one fixed
+// step instead, and only while STEP commands have ticks left, so positions and frames
+// come out the same on every run.
+#[derive(Resource)]
+pub struct Simulation {
+    pub deterministic: bool,
+    pub paused: bool,
+    // Simulated seconds per real second
+    pub speed: f32,
+    // Unix time of the first tick in deterministic mode
+    start_time: Duration,
+    // Ticks run so far
+    pub tick: u64,
+    // Ticks left to run
+    steps: u64,
+    // Waiting for the STEP command to finish
+    reply: Option<Sender<String>>,
+}
+
+impl Simulation {
+    pub fn new(config: &config::SimulationConfig) -> Self {
+        Self {
+            deterministic: config.deterministic,
+            paused: false,
+            speed: 1.0,
+            start_time: Duration::from_secs_f64(config.start_time.max(0.0)),
+            tick: 0,
+            steps: 0,
+            reply: None,
+        }
+    }
+
+    // The wall clock, or in deterministic mode the start time plus the time simulated
+    // until elapsed.
+    pub fn now(&self, elapsed: Duration) -> SystemTime {
+        if self.deterministic {
+            UNIX_EPOCH + self.start_time + elapsed
+        } else {
+            SystemTime::now()
+        }
+    }
+
+    pub fn unix_time_us(&self, elapsed: Duration) -> u64 {
+        self.now(elapsed)
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0)
+    }
+
+    // Ignores speeds that are not a number, the clock can't run at those.
+    pub fn set_speed(&mut self, speed: f32) {
+        if speed.is_finite() {
+            self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        }
+    }
+
+    // Simulated time, the speed and whether it is paused, for status lines and overlays.
+    pub fn describe(&self, elapsed: Duration) -> String {
+        let millis = elapsed.as_millis();
+        let state = if self.deterministic {
+            format!("tick {}", self.tick)
+        } else if self.paused {
+            "paused".to_string()
+        } else {
+            format!("x{:.1}", self.speed)
+        };
+        format!(
+            "{:02}:{:02}:{:02}.{:03} {}",
+            millis / 3_600_000,
+            millis / 60_000 % 60,
+            millis / 1000 % 60,
+            millis % 1000,
+            state
+        )
+    }
+
+    // Runs ticks more ticks, tx gets the tick reached once they are done.
+    pub fn step(&mut self, ticks: u64, tx: Sender<String>) {
+        if !self.deterministic {
+            let _ = tx.send("Not in deterministic mode.\r\n".to_string());
+            return;
+        }
+        if let Some(previous) = self.reply.replace(tx) {
+            let _ = previous.send("Interrupted by another STEP.\r\n".to_string());
+        }
+        self.steps += ticks;
+    }
+}
+
+// Random numbers for the scene, the same on every run when a seed is set.
+pub fn rng(config: &config::Config) -> StdRng {
+    match config.environment.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None if config.simulation.deterministic => StdRng::seed_from_u64(0),
+        None => StdRng::from_rng(&mut rand::rng()),
+    }
+}
+
+// Makes every app update advance the time by exactly step in deterministic mode.
+pub fn fixed_step(config: &config::SimulationConfig, step: Duration) -> TimeUpdateStrategy {
+    if config.deterministic {
+        TimeUpdateStrategy::ManualDuration(step)
+    } else {
+        TimeUpdateStrategy::Automatic
+    }
+}
+
+// Runs before the time is updated so pausing and the speed apply from this frame on, and
+// the virtual clock only moves on ticks in deterministic mode. A tick only runs once the
+// frames of the previous one have reached the stream writers, so STEP replies after its
+// frames are out.
+pub fn advance(
+    mut simulation: ResMut<Simulation>,
+    mut time: ResMut<Time<Virtual>>,
+    streams: Query<&stream::CameraStream>,
+) {
+    if !simulation.deterministic {
+        time.set_relative_speed(simulation.speed);
+        if simulation.paused {
+            time.pause();
+        } else {
+            time.unpause();
+        }
+    } else if !streams.iter().all(|stream| stream.is_written()) {
+        time.pause();
+    } else if simulation.steps > 0 {
+        simulation.steps -= 1;
+        simulation.tick += 1;
+        time.unpause();
+    } else {
+        time.pause();
+        if let Some(tx) = simulation.reply.take() {
+            let _ = tx.send(format!("Tick {}\r\n", simulation.tick));
+        }
+    }
+}
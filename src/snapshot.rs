@@ -0,0 +1,182 @@
+use crate::config;
+use crate::stream;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::{ExtendedColorType, ImageEncoder};
+use serde::Serialize;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// A still of the radar camera with the pedestal state of the frame it was taken from.
+pub struct Snapshot {
+    pub image: Vec<u8>,
+    pub format: config::ImageFormat,
+    pub info: SnapshotInfo,
+}
+
+#[derive(Serialize)]
+pub struct SnapshotInfo {
+    // Stream frame the snapshot was taken from
+    pub frame: u64,
+    // Unix time in seconds of the capture
+    pub time: f64,
+    pub azimuth: f32,
+    pub elevation: f32,
+    pub horizontal_fov: f32,
+    pub vertical_fov: f32,
+}
+
+impl Snapshot {
+    // Encodes the latest frame written to frame_buffer.
+    pub fn take(
+        frame_buffer: &stream::FrameBuffer,
+        format: config::ImageFormat,
+        quality: u8,
+    ) -> Result<Self, String> {
+        let (rgb, frame) = frame_buffer.rgb_frame();
+        if frame.number == 0 {
+            return Err("no frame captured yet".to_string());
+        }
+        let telemetry = frame
+            .telemetry
+            .ok_or_else(|| "frame has no pedestal state".to_string())?;
+        let mut image = Vec::new();
+        let (width, height) = (frame_buffer.width, frame_buffer.height);
+        let encoded = match format {
+            config::ImageFormat::Png => PngEncoder::new(&mut image).write_image(
+                &rgb,
+                width,
+                height,
+                ExtendedColorType::Rgb8,
+            ),
+            config::ImageFormat::Jpeg => JpegEncoder::new_with_quality(
+                &mut image,
+                quality.clamp(1, 100),
+            )
+            .encode(&rgb, width, height, ExtendedColorType::Rgb8),
+        };
+        encoded.map_err(|e| format!("failed to encode snapshot: {}", e))?;
+        Ok(Self {
+            image,
+            format,
+            info: SnapshotInfo {
+                frame: frame.number,
+                time: telemetry.timestamp_us as f64 / 1e6,
+                azimuth: telemetry.azimuth,
+                elevation: telemetry.elevation,
+                horizontal_fov: telemetry.horizontal_fov,
+                vertical_fov: telemetry.vertical_fov,
+            },
+        })
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.format {
+            config::ImageFormat::Png => "png",
+            config::ImageFormat::Jpeg => "jpg",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self.format {
+            config::ImageFormat::Png => "image/png",
+            config::ImageFormat::Jpeg => "image/jpeg",
+        }
+    }
+
+    // Writes the image to directory with its pedestal state in a JSON file next to it.
+    pub fn save(&self, directory: &str) -> Result<PathBuf, String> {
+        let directory = PathBuf::from(directory);
+        fs::create_dir_all(&directory).map_err(|e| e.to_string())?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let name = format!("snapshot_{}_{}", millis, self.info.frame);
+        let path = directory.join(format!("{}.{}", name, self.extension()));
+        fs::write(&path, &self.image).map_err(|e| e.to_string())?;
+        let info = serde_json::to_string_pretty(&self.info).map_err(|e| e.to_string())?;
+        fs::write(directory.join(format!("{}.json", name)), info).map_err(|e| e.to_string())?;
+        Ok(path)
+    }
+}
+
+// Serves the radar camera at http://127.0.0.1:<port>/snapshot.png or /snapshot.jpg, in
+// the configured format at /snapshot, with the pedestal state in X- headers.
+pub fn start_server(config: &config::SnapshotConfig, port: u16, frame_buffer: stream::FrameBuffer) {
+    let address = format!("127.0.0.1:{}", port);
+    let config = config.clone();
+    thread::spawn(move || {
+        let listener = TcpListener::bind(&address).expect("Could not bind snapshot address");
+        println!("Snapshots available on http://{}/snapshot", address);
+        for stream in listener.incoming() {
+            match stream {
+                // Encoding a snapshot takes a while, do not make other clients wait on it
+                Ok(stream) => {
+                    let config = config.clone();
+                    let frame_buffer = frame_buffer.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = respond(stream, &config, &frame_buffer) {
+                            eprintln!("Failed to answer a snapshot request: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept a snapshot connection: {:?}", e),
+            }
+        }
+    });
+}
+
+fn respond(
+    mut stream: TcpStream,
+    config: &config::SnapshotConfig,
+    frame_buffer: &stream::FrameBuffer,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let format = match request.split_whitespace().nth(1).unwrap_or("/") {
+        "/snapshot" => config.format,
+        "/snapshot.png" => config::ImageFormat::Png,
+        "/snapshot.jpg" | "/snapshot.jpeg" => config::ImageFormat::Jpeg,
+        _ => {
+            return stream.write_all(
+                b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+        }
+    };
+    let snapshot = match Snapshot::take(frame_buffer, format, config.quality) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            return write!(
+                stream,
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                e.len(),
+                e
+            );
+        }
+    };
+    let info = &snapshot.info;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nX-Frame: {}\r\nX-Time: {:.6}\r\nX-Azimuth: {}\r\nX-Elevation: {}\r\nX-Horizontal-Fov: {}\r\nX-Vertical-Fov: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        snapshot.content_type(),
+        snapshot.image.len(),
+        info.frame,
+        info.time,
+        info.azimuth,
+        info.elevation,
+        info.horizontal_fov,
+        info.vertical_fov
+    )?;
+    stream.write_all(&snapshot.image)
+}
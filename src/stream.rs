@@ -1,23 +1,141 @@
+use crate::config;
+use crate::klv;
+use crate::metrics;
+#[cfg(feature = "native-encoder")]
+use crate::native_stream;
+use crate::radar;
+use crate::readback;
+#[cfg(feature = "rtsp-server")]
+use crate::rtsp_server;
+use crate::simulation;
+use crate::supervisor;
 use bevy::prelude::*;
-use bevy::render::view::screenshot::{Screenshot, ScreenshotCaptured};
-use std::io::Write;
-use std::ops::Deref;
-use std::process::{ChildStdin, Command, Stdio};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-#[derive(Resource)]
-pub struct CameraRenderTexture {
+// One encoded stream of a camera render target.
+#[derive(Component)]
+pub struct CameraStream {
+    pub camera: Entity,
+    pub path: String,
     pub handle: Handle<Image>,
-    pub ffmpeg_stdin: ChildStdin,
+    pub frame_buffer: FrameBuffer,
+    // Number of the frame captured on the latest tick, starting at 1
+    pub frame_number: u64,
+    pub stats: Arc<FrameStats>,
+    frame_interval: f32,
+    since_last_frame: f32,
+    frame_tx: SyncSender<CapturedFrame>,
+    taps: Arc<Mutex<Vec<Sender<WrittenFrame>>>>,
 }
 
-#[derive(Resource)]
+impl CameraStream {
+    // Every frame handed to the encoder from now on, in the same order.
+    pub fn subscribe(&self) -> Receiver<WrittenFrame> {
+        let (tx, rx) = mpsc::channel();
+        self.taps.lock().unwrap().push(tx);
+        rx
+    }
+
+    // Whether the writer is done with the frame of the latest tick.
+    pub fn is_written(&self) -> bool {
+        self.stats.last_frame.load(Ordering::Acquire) >= self.frame_number
+    }
+}
+
+// A frame as it was written to the encoder, a duplicate keeps the number of the original.
+#[derive(Clone)]
+pub struct WrittenFrame {
+    pub number: u64,
+    pub data: Arc<Vec<u8>>,
+}
+
+// A screenshot of a stream, tagged with the tick it was requested on.
+struct CapturedFrame {
+    number: u64,
+    requested: Instant,
+    data: Arc<Vec<u8>>,
+    // Pedestal state at capture for the stream metadata and snapshots
+    telemetry: Option<klv::Telemetry>,
+}
+
+// Frames of a stream in the order they passed through the writer.
+#[derive(Default)]
+pub struct FrameStats {
+    pub captured: AtomicU64,
+    pub written: AtomicU64,
+    // Frames that never arrived in time or that the encoder could not take
+    pub dropped: AtomicU64,
+    // Copies of the previous frame written in place of dropped ones
+    pub duplicated: AtomicU64,
+    // Microseconds from capture until the last frame was handed to the encoder
+    pub latency_us: AtomicU64,
+    pub bytes_written: AtomicU64,
+    // Number of the last frame the writer is done with
+    pub last_frame: AtomicU64,
+}
+
+impl FrameStats {
+    pub fn summary(&self) -> String {
+        format!(
+            "captured {} written {} dropped {} duplicated {} latency {:.1} ms",
+            self.captured.load(Ordering::Relaxed),
+            self.written.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.duplicated.load(Ordering::Relaxed),
+            self.latency_us.load(Ordering::Relaxed) as f32 / 1000.0
+        )
+    }
+}
+
+// Frames that may arrive out of order before a missing one is given up on.
+const REORDER_WINDOW: usize = 3;
+// Captured frames waiting for the writer, more are dropped while the encoder is behind.
+const FRAME_QUEUE: usize = 4;
+
+// Where the raw frames of a stream are sent to be encoded.
+enum FrameSink {
+    Ffmpeg(Arc<supervisor::Process>),
+    #[cfg(feature = "native-encoder")]
+    Native(native_stream::NativeStream),
+    // Streaming is turned off, frames are only captured.
+    Disabled,
+}
+
+impl FrameSink {
+    // Whether the whole frame was handed to the encoder.
+    fn write_frame(&mut self, frame: &[u8]) -> bool {
+        match self {
+            FrameSink::Ffmpeg(ffmpeg) => ffmpeg.write(frame),
+            #[cfg(feature = "native-encoder")]
+            FrameSink::Native(native) => native.write_frame(frame),
+            FrameSink::Disabled => true,
+        }
+    }
+}
+
+#[derive(Resource, Clone)]
 pub struct FrameBuffer {
     pub width: u32,
     pub height: u32,
     buffer: Arc<Mutex<Vec<u8>>>,
+    // Number and pedestal state of the frame in buffer
+    frame: Arc<Mutex<FrameInfo>>,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct FrameInfo {
+    // 0 until the first frame is written
+    pub number: u64,
+    pub telemetry: Option<klv::Telemetry>,
+    // Mean luma between 0 and 1
+    pub luminance: f32,
 }
 
 impl Default for FrameBuffer {
@@ -30,6 +148,7 @@ impl Default for FrameBuffer {
             width,
             height,
             buffer: Arc::new(Mutex::new(vec![0u8; buffer_size])),
+            frame: Arc::default(),
         }
     }
 }
@@ -41,132 +160,716 @@ impl FrameBuffer {
             width,
             height,
             buffer: Arc::new(Mutex::new(vec![0u8; size])),
+            frame: Arc::default(),
+        }
+    }
+
+    fn store(&self, frame: &CapturedFrame) {
+        let luminance = self.mean_luminance(&frame.data);
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.copy_from_slice(&frame.data);
+        *self.frame.lock().unwrap() = FrameInfo {
+            number: frame.number,
+            telemetry: frame.telemetry,
+            luminance,
+        };
+    }
+
+    // Number, pedestal state and brightness of the current frame.
+    pub fn info(&self) -> FrameInfo {
+        *self.frame.lock().unwrap()
+    }
+
+    // Copy of the current frame as packed RGB, with its number and pedestal state.
+    pub fn rgb_frame(&self) -> (Vec<u8>, FrameInfo) {
+        let buffer = self.buffer.lock().unwrap();
+        let frame = *self.frame.lock().unwrap();
+        let rgb = buffer
+            .chunks_exact(4)
+            .flat_map(|bgra| [bgra[2], bgra[1], bgra[0]])
+            .collect();
+        (rgb, frame)
+    }
+
+    // Copy of the current BGRA frame as packed RGB.
+    pub fn rgb(&self) -> Vec<u8> {
+        self.rgb_frame().0
+    }
+
+    // Mean luma between 0 and 1, sampled on a sparse grid of the BGRA frame.
+    fn mean_luminance(&self, buffer: &[u8]) -> f32 {
+        const STEP: usize = 16;
+        let row = self.width as usize * 4;
+        let mut sum = 0.0;
+        let mut count = 0;
+        for y in (0..self.height as usize).step_by(STEP) {
+            for x in (0..self.width as usize).step_by(STEP) {
+                let i = y * row + x * 4;
+                let (b, g, r) = (buffer[i] as f32, buffer[i + 1] as f32, buffer[i + 2] as f32);
+                sum += 0.114 * b + 0.587 * g + 0.299 * r;
+                count += 1;
+            }
+        }
+        if count == 0 {
+            return 0.0;
         }
+        sum / count as f32 / 255.0
     }
 }
 
-pub fn start_stream(commands: &mut Commands, image: Handle<Image>, width: u32, height: u32) {
+fn exe_dir() -> std::path::PathBuf {
     // Get the directory where the current executable is located
-    let exe_dir = std::env::current_exe()
+    std::env::current_exe()
         .expect("Failed to get current executable path")
         .parent()
         .expect("Failed to get executable directory")
-        .to_path_buf();
+        .to_path_buf()
+}
 
-    let mediamtx_path = exe_dir.join(if cfg!(windows) {
-        "mediamtx.exe"
-    } else {
-        "mediamtx"
-    });
-    let ffmpeg_path = exe_dir.join(if cfg!(windows) {
+// The ffmpeg executable copied next to ours.
+pub fn ffmpeg_path() -> std::path::PathBuf {
+    exe_dir().join(if cfg!(windows) {
         "ffmpeg.exe"
     } else {
         "ffmpeg"
+    })
+}
+
+fn start_mediamtx() {
+    let mediamtx_path = exe_dir().join(if cfg!(windows) {
+        "mediamtx.exe"
+    } else {
+        "mediamtx"
     });
 
-    let mut mediamtx = Command::new(&mediamtx_path)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .spawn()
-        .expect("Failed to start mediamtx");
+    supervisor::spawn("mediamtx", move || {
+        let mut mediamtx = Command::new(&mediamtx_path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        log_output("mediamtx", mediamtx.stdout.take());
+        Ok(mediamtx)
+    });
 
     thread::sleep(Duration::from_secs(1));
+}
 
-    if let Some(mediamtx_stderr) = mediamtx.stdout.take() {
-        std::thread::spawn(move || {
-            use std::io::{BufRead, BufReader};
-            let reader = BufReader::new(mediamtx_stderr);
-            for line in reader.lines() {
-                match line {
-                    Ok(log) => println!("mediamtx Log: {}", log),
-                    Err(e) => eprintln!("Error reading mediamtx stderr: {}", e),
+// Starts whatever serves the streams to clients, before any stream is started.
+pub fn start_servers(config: &config::StreamConfig) {
+    if !config.enabled {
+        return;
+    }
+    match config.rtsp_server {
+        // Native streams and remote urls do not go through mediamtx.
+        config::RtspServer::Mediamtx => {
+            if config.encoder == config::Encoder::Ffmpeg && config.is_local_rtsp() {
+                start_mediamtx();
+            }
+        }
+        #[cfg(feature = "rtsp-server")]
+        config::RtspServer::Builtin => rtsp_server::start_server(config.host(), config.port()),
+        #[cfg(not(feature = "rtsp-server"))]
+        config::RtspServer::Builtin => unreachable!("checked when loading the config"),
+    }
+    #[cfg(feature = "native-encoder")]
+    if config.encoder == config::Encoder::Native {
+        native_stream::start_server(config.native_port);
+    }
+}
+
+pub fn stream_url(config: &config::StreamConfig, path: &str) -> String {
+    match (config.rtsp_server, config.encoder) {
+        #[cfg(feature = "rtsp-server")]
+        (config::RtspServer::Builtin, _) => rtsp_server::url(config.host(), config.port(), path),
+        #[cfg(not(feature = "rtsp-server"))]
+        (config::RtspServer::Builtin, _) => unreachable!("checked when loading the config"),
+        (config::RtspServer::Mediamtx, config::Encoder::Ffmpeg) => config.url_for(path),
+        #[cfg(feature = "native-encoder")]
+        (config::RtspServer::Mediamtx, config::Encoder::Native) => {
+            native_stream::url(config.native_port, path)
+        }
+        #[cfg(not(feature = "native-encoder"))]
+        (config::RtspServer::Mediamtx, config::Encoder::Native) => {
+            unreachable!("checked when loading the config")
+        }
+    }
+}
+
+pub fn start_stream(
+    commands: &mut Commands,
+    camera: Entity,
+    image: Handle<Image>,
+    frame_buffer: FrameBuffer,
+    path: &str,
+    frame_rate: u32,
+    config: &config::Config,
+) {
+    // Frames come slower than real time when stepped, so they get timestamps from the rate.
+    let wallclock = !config.simulation.deterministic;
+    let config = &config.stream;
+    let (sink, metadata_tx) = match config.encoder {
+        _ if !config.enabled => (FrameSink::Disabled, None),
+        config::Encoder::Ffmpeg => start_ffmpeg(&frame_buffer, path, frame_rate, config, wallclock),
+        #[cfg(feature = "native-encoder")]
+        config::Encoder::Native => {
+            let (metadata_tx, metadata_rx) = config.metadata.then(mpsc::channel).unzip();
+            #[cfg(feature = "rtsp-server")]
+            let forward = (config.rtsp_server == config::RtspServer::Builtin).then(|| {
+                let mount = rtsp_server::mount(path);
+                Box::new(move |access_unit: &[u8]| mount.send(access_unit))
+                    as Box<dyn FnMut(&[u8]) + Send>
+            });
+            #[cfg(not(feature = "rtsp-server"))]
+            let forward = None;
+            let native = native_stream::start_stream(
+                path,
+                frame_buffer.width,
+                frame_buffer.height,
+                frame_rate,
+                config,
+                metadata_rx,
+                forward,
+            );
+            (FrameSink::Native(native), metadata_tx)
+        }
+        #[cfg(not(feature = "native-encoder"))]
+        config::Encoder::Native => unreachable!("checked when loading the config"),
+    };
+
+    let stats = Arc::new(FrameStats::default());
+    metrics::register_stream(path, stats.clone());
+    let (frame_tx, frame_rx) = mpsc::sync_channel(FRAME_QUEUE);
+    let writer_buffer = frame_buffer.clone();
+    let writer_stats = stats.clone();
+    let taps = Arc::new(Mutex::new(Vec::new()));
+    let writer_taps = taps.clone();
+    thread::spawn(move || {
+        run_writer(
+            frame_rx,
+            sink,
+            writer_buffer,
+            metadata_tx,
+            writer_stats,
+            writer_taps,
+        );
+    });
+
+    commands.spawn(CameraStream {
+        camera,
+        path: path.to_string(),
+        handle: image,
+        frame_buffer,
+        frame_number: 0,
+        stats,
+        frame_interval: 1.0 / frame_rate as f32,
+        since_last_frame: 0.0,
+        frame_tx,
+        taps,
+    });
+}
+
+// Writes the captured frames of a stream to its encoder exactly once and in frame order.
+// Frames may come back from the GPU out of order or not at all, a frame still missing
+// once REORDER_WINDOW later ones are waiting or whose readback failed is replaced by a
+// copy of the previous one so the encoder keeps its frame rate.
+fn run_writer(
+    frame_rx: Receiver<CapturedFrame>,
+    mut sink: FrameSink,
+    frame_buffer: FrameBuffer,
+    metadata_tx: Option<Sender<Vec<u8>>>,
+    stats: Arc<FrameStats>,
+    taps: Arc<Mutex<Vec<Sender<WrittenFrame>>>>,
+) {
+    let mut pending = BTreeMap::new();
+    let mut next = 1;
+    let mut previous: Option<CapturedFrame> = None;
+    let write = |frame: &CapturedFrame, sink: &mut FrameSink| {
+        if let (Some(metadata_tx), Some(telemetry)) = (&metadata_tx, &frame.telemetry) {
+            let _ = metadata_tx.send(klv::encode_local_set(telemetry));
+        }
+        if sink.write_frame(&frame.data) {
+            stats.written.fetch_add(1, Ordering::Relaxed);
+            stats
+                .bytes_written
+                .fetch_add(frame.data.len() as u64, Ordering::Relaxed);
+        } else {
+            stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        let written = WrittenFrame {
+            number: frame.number,
+            data: frame.data.clone(),
+        };
+        taps.lock()
+            .unwrap()
+            .retain(|tap| tap.send(written.clone()).is_ok());
+    };
+    while let Ok(frame) = frame_rx.recv() {
+        // Too late, a copy of the previous frame has already been written in its place.
+        if frame.number < next {
+            continue;
+        }
+        pending.insert(frame.number, frame);
+        loop {
+            let missing = match pending.remove(&next) {
+                Some(frame) if !frame.data.is_empty() => {
+                    frame_buffer.store(&frame);
+                    write(&frame, &mut sink);
+                    let latency = frame.requested.elapsed().as_micros() as u64;
+                    stats.latency_us.store(latency, Ordering::Relaxed);
+                    previous = Some(frame);
+                    false
+                }
+                // The readback failed.
+                Some(_) => true,
+                None if pending.len() > REORDER_WINDOW => true,
+                None => break,
+            };
+            if missing {
+                stats.dropped.fetch_add(1, Ordering::Relaxed);
+                if let Some(previous) = &previous {
+                    write(previous, &mut sink);
+                    stats.duplicated.fetch_add(1, Ordering::Relaxed);
                 }
             }
-        });
+            stats.last_frame.store(next, Ordering::Release);
+            next += 1;
+        }
     }
+}
 
-    let mut ffmpeg = Command::new(&ffmpeg_path)
-        .args([
-            "-fflags",
-            "+genpts",
-            "-fflags",
-            "nobuffer",
-            "-f",
-            "rawvideo", // Input format is raw video
-            "-video_size",
-            &format!("{}x{}", width, height), // Replace with your texture size
-            "-framerate",
-            "25", // Replace with your target framerate
-            "-use_wallclock_as_timestamps",
-            "1",
-            "-pixel_format",
-            "bgra",
-            "-i",
-            "-", // Read from stdin
-            "-c:v",
-            "libx264", // Encode to H.264
-            "-r",
-            "25", // Output format
-            "-g",
-            "25",
-            "-pix_fmt",
-            "yuv420p",
-            "-preset",
-            "ultrafast",
-            "-f",
-            "rtsp", // Output format
-            "-rtsp_transport",
-            "udp",
-            "rtsp://127.0.0.1:8554/live", // RTSP output URL
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::null())
-        .stderr(Stdio::piped())
-        .spawn()
-        .expect("Failed to start FFmpeg");
-
-    let ffmpeg_stdin = ffmpeg.stdin.take().expect("Failed to capture FFmpeg stdin");
-    thread::sleep(Duration::from_secs(1));
+// ffmpeg arguments that encode with the configured codec, bitrate, GOP and pixel format.
+pub fn codec_args(config: &config::StreamConfig, frame_rate: u32) -> Vec<String> {
+    let (codec, default_pixel_format) = match config.codec {
+        config::Codec::H264 => ("libx264", "yuv420p"),
+        config::Codec::H265 => ("libx265", "yuv420p"),
+        config::Codec::Mjpeg => ("mjpeg", "yuvj420p"),
+    };
+    let gop = config.gop.unwrap_or(frame_rate).to_string();
+    let pixel_format = config
+        .pixel_format
+        .as_deref()
+        .unwrap_or(default_pixel_format);
+    let mut args = vec!["-c:v", codec, "-g", gop.as_str(), "-pix_fmt", pixel_format];
+    if config.codec != config::Codec::Mjpeg {
+        args.extend(["-preset", "ultrafast"]);
+    }
+    let mut args: Vec<String> = args.into_iter().map(String::from).collect();
+    if let Some(bitrate) = config.bitrate {
+        args.extend(["-b:v".to_string(), format!("{}k", bitrate)]);
+    }
+    args
+}
+
+// ffmpeg arguments that publish to url, with the container picked from its scheme.
+fn output_args(config: &config::StreamConfig, url: String) -> Vec<String> {
+    let scheme = url.split("://").next().unwrap_or_default();
+    let mut args: Vec<String> = match scheme {
+        "rtsp" => {
+            let transport = match config.transport {
+                config::Transport::Udp => "udp",
+                config::Transport::Tcp => "tcp",
+            };
+            ["-f", "rtsp", "-rtsp_transport", transport]
+                .map(String::from)
+                .to_vec()
+        }
+        "rtmp" => ["-f", "flv"].map(String::from).to_vec(),
+        "udp" | "srt" | "tcp" => ["-f", "mpegts"].map(String::from).to_vec(),
+        // Anything else, such as a file name, lets ffmpeg guess the format.
+        _ => Vec::new(),
+    };
+    args.push(url);
+    args
+}
+
+fn start_ffmpeg(
+    frame_buffer: &FrameBuffer,
+    path: &str,
+    frame_rate: u32,
+    config: &config::StreamConfig,
+    wallclock: bool,
+) -> (FrameSink, Option<Sender<Vec<u8>>>) {
+    let metadata = config.metadata;
+    let ffmpeg_path = ffmpeg_path();
 
-    if let Some(stderr) = ffmpeg.stderr.take() {
+    let size = format!("{}x{}", frame_buffer.width, frame_buffer.height);
+    let rate = frame_rate.to_string();
+    let input_args: Vec<String> = [
+        "-fflags",
+        "+genpts",
+        "-fflags",
+        "nobuffer",
+        "-f",
+        "rawvideo", // Input format is raw video
+        "-video_size",
+        &size,
+        "-framerate",
+        &rate,
+        "-use_wallclock_as_timestamps",
+        if wallclock { "1" } else { "0" },
+        "-pixel_format",
+        "bgra",
+        "-i",
+        "-", // Read from stdin
+    ]
+    .map(String::from)
+    .to_vec();
+    let output_args = output_args(config, config.url_for(path));
+    let codec_args = codec_args(config, frame_rate);
+
+    let builtin = config.rtsp_server == config::RtspServer::Builtin;
+    let (ffmpeg, metadata_tx) = if metadata || builtin {
+        // The encoder writes an elementary stream to stdout that is split into access units
+        // at each delimiter, where the telemetry SEI is inserted, and sent on to the built
+        // in RTSP server or an ffmpeg publisher for mediamtx. Every input frame must give
+        // exactly one access unit to keep them in step.
+        let (metadata_tx, metadata_rx) = metadata.then(mpsc::channel).unzip();
+        let metadata_rx = metadata_rx.map(|rx| Arc::new(Mutex::new(rx)));
+        #[allow(clippy::type_complexity)]
+        let send: Box<dyn FnMut(&[u8]) -> bool + Send> = if builtin {
+            #[cfg(feature = "rtsp-server")]
+            {
+                let mount = rtsp_server::mount(path);
+                Box::new(move |access_unit| {
+                    mount.send(access_unit);
+                    true
+                })
+            }
+            #[cfg(not(feature = "rtsp-server"))]
+            unreachable!("checked when loading the config");
+        } else {
+            let publisher_path = ffmpeg_path.clone();
+            let rate = rate.clone();
+            let output_args = output_args.clone();
+            let publisher = supervisor::spawn(format!("FFmpeg publisher {}", path), move || {
+                let mut publisher = Command::new(&publisher_path)
+                    .args([
+                        "-fflags",
+                        "+genpts",
+                        "-f",
+                        "h264",
+                        "-framerate",
+                        &rate,
+                        "-i",
+                        "-",
+                        "-c:v",
+                        "copy",
+                    ])
+                    .args(&output_args)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()?;
+                log_output("FFmpeg publisher", publisher.stderr.take());
+                Ok(publisher)
+            });
+            // Keep encoding while the publisher restarts.
+            Box::new(move |access_unit| {
+                publisher.write(access_unit);
+                true
+            })
+        };
+        // Outlives the encoder, a restarted one sends to the same server or publisher.
+        let send = Arc::new(Mutex::new(send));
+        let encoder = supervisor::spawn(format!("FFmpeg {}", path), move || {
+            let mut encoder = Command::new(&ffmpeg_path)
+                .args(&input_args)
+                .args(&codec_args)
+                .args([
+                    "-bf",
+                    "0",
+                    "-x264-params",
+                    "aud=1",
+                    "-fps_mode",
+                    "passthrough",
+                    "-f",
+                    "h264",
+                    "-",
+                ])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()?;
+            let encoded = encoder
+                .stdout
+                .take()
+                .expect("Failed to capture FFmpeg stdout");
+            // Metadata of frames the previous encoder never finished is stale.
+            if let Some(rx) = &metadata_rx {
+                while rx.lock().unwrap().try_recv().is_ok() {}
+            }
+            let metadata_rx = metadata_rx.clone();
+            let send = send.clone();
+            thread::spawn(move || {
+                forward_access_units(encoded, metadata_rx, |access_unit| {
+                    (send.lock().unwrap())(access_unit)
+                });
+            });
+            log_output("FFmpeg", encoder.stderr.take());
+            Ok(encoder)
+        });
+        (encoder, metadata_tx)
+    } else {
+        let ffmpeg = supervisor::spawn(format!("FFmpeg {}", path), move || {
+            let mut ffmpeg = Command::new(&ffmpeg_path)
+                .args(&input_args)
+                .args(["-r", &rate]) // Output frame rate
+                .args(&codec_args)
+                .args(&output_args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .spawn()?;
+            log_output("FFmpeg", ffmpeg.stderr.take());
+            Ok(ffmpeg)
+        });
+        (ffmpeg, None)
+    };
+    if !builtin {
+        // Give the publisher time to connect to the server.
+        thread::sleep(Duration::from_secs(1));
+    }
+    (FrameSink::Ffmpeg(ffmpeg), metadata_tx)
+}
+
+pub fn log_output(name: &'static str, output: Option<impl Read + Send + 'static>) {
+    if let Some(output) = output {
         std::thread::spawn(move || {
-            use std::io::{BufRead, BufReader};
-            let reader = BufReader::new(stderr);
+            let reader = BufReader::new(output);
             for line in reader.lines() {
                 match line {
-                    Ok(log) => println!("FFmpeg Log: {}", log),
-                    Err(e) => eprintln!("Error reading FFmpeg stderr: {}", e),
+                    Ok(log) => println!("{} Log: {}", name, log),
+                    Err(e) => eprintln!("Error reading {} stderr: {}", name, e),
                 }
             }
         });
     }
+}
 
-    commands.insert_resource(CameraRenderTexture {
-        handle: image,
-        ffmpeg_stdin: ffmpeg_stdin,
-    });
+// Position of the next access unit delimiter start code at or after `from`.
+fn find_delimiter(data: &[u8], from: usize) -> Option<usize> {
+    (from..data.len().saturating_sub(3))
+        .find(|&i| data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 && data[i + 3] & 0x1F == 9)
+}
+
+// Splits the H.264 elementary stream from the encoder into access units and hands them on
+// with the next telemetry SEI inserted after the delimiter, until `send` fails.
+fn forward_access_units(
+    mut encoded: impl Read,
+    metadata_rx: Option<Arc<Mutex<Receiver<Vec<u8>>>>>,
+    mut send: impl FnMut(&[u8]) -> bool,
+) {
+    let mut chunk = [0u8; 64 * 1024];
+    let mut pending = Vec::new();
+    let mut access_unit = Vec::new();
+    loop {
+        let n = match encoded.read(&mut chunk) {
+            Ok(n) => n,
+            Err(e) => {
+                eprintln!("Error reading FFmpeg output: {}", e);
+                0
+            }
+        };
+        pending.extend_from_slice(&chunk[..n]);
+
+        // An access unit is complete once the delimiter of the next one shows up, the
+        // first bytes are skipped as they hold the delimiter of the current one.
+        loop {
+            let end = match find_delimiter(&pending, 4) {
+                // A four byte start code has an extra leading zero.
+                Some(next) if pending[next - 1] == 0 => next - 1,
+                Some(next) => next,
+                // Whatever is left once the encoder is done is the last access unit.
+                None if n == 0 && !pending.is_empty() => pending.len(),
+                None => break,
+            };
+            access_unit.clear();
+            access_unit.extend_from_slice(&pending[..end]);
+            // Start code, NAL header with type 9 and the primary_pic_type byte.
+            if let (Some(delimiter), Some(payload)) = (
+                find_delimiter(&access_unit, 0),
+                metadata_rx
+                    .as_ref()
+                    .and_then(|rx| rx.lock().unwrap().try_recv().ok()),
+            ) {
+                let insert_at = (delimiter + 5).min(access_unit.len());
+                access_unit.splice(insert_at..insert_at, klv::sei_nal(&payload));
+            }
+            if !send(&access_unit) {
+                return;
+            }
+            pending.drain(..end);
+        }
+        if n == 0 {
+            return;
+        }
+    }
 }
 
 pub fn stream_frames(
-    mut resource: ResMut<CameraRenderTexture>,
-    mut commands: Commands,
-    frame_buffer: Res<FrameBuffer>,
+    mut streams: Query<(Entity, &mut CameraStream)>,
+    mut readbacks: ResMut<readback::ReadbackQueue>,
+    radar: Res<radar::Radar>,
+    time: Res<Time>,
+    real_time: Res<Time<Real>>,
+    simulation: Res<simulation::Simulation>,
+    query: Query<&Projection>,
 ) {
-    let buffer_clone = frame_buffer.buffer.clone();
-    let sc = Screenshot::image(resource.handle.clone());
-    commands.spawn(sc).observe(save_to_buffer(buffer_clone));
-    let buffer = frame_buffer.buffer.lock().unwrap();
-    let _ = resource.ffmpeg_stdin.write(&buffer);
-}
-
-fn save_to_buffer(buffer: Arc<Mutex<Vec<u8>>>) -> impl FnMut(Trigger<ScreenshotCaptured>) {
-    move |trigger| {
-        let img = trigger.event().deref().clone();
-        if let Some(data) = &img.data {
-            let mut buffer = buffer.lock().unwrap();
-            buffer.copy_from_slice(data);
+    // Streams run on the real clock, pausing or speeding up the simulation only changes what
+    // they show. Stepped runs capture once per tick, which is a frame of the fastest stream.
+    let delta = if simulation.deterministic {
+        time.delta_secs()
+    } else {
+        real_time.delta_secs()
+    };
+    for (entity, mut stream) in streams.iter_mut() {
+        stream.since_last_frame += delta;
+        if stream.since_last_frame + 1e-4 < stream.frame_interval {
+            continue;
+        }
+        // Capture at most once per app frame without building up a backlog.
+        stream.since_last_frame =
+            (stream.since_last_frame - stream.frame_interval).clamp(0.0, stream.frame_interval);
+        stream.frame_number += 1;
+        let frame_buffer = &stream.frame_buffer;
+        let timestamp_us = simulation.unix_time_us(time.elapsed());
+        let telemetry = match query.get(stream.camera) {
+            Ok(Projection::Perspective(perspective)) => {
+                let aspect_ratio = frame_buffer.width as f32 / frame_buffer.height as f32;
+                let horizontal_fov = 2.0 * ((perspective.fov / 2.0).tan() * aspect_ratio).atan();
+                Some(klv::Telemetry {
+                    timestamp_us,
+                    azimuth: radar.current.azimuth,
+                    elevation: radar.current.elevation,
+                    horizontal_fov: horizontal_fov.to_degrees(),
+                    vertical_fov: perspective.fov.to_degrees(),
+                })
+            }
+            _ => None,
+        };
+        let size = frame_buffer.width as usize * frame_buffer.height as usize * 4;
+        readbacks.request(readback::ReadbackRequest {
+            target: entity,
+            image: stream.handle.clone(),
+            on_complete: capture_frame(
+                stream.frame_tx.clone(),
+                stream.frame_number,
+                size,
+                telemetry,
+                stream.stats.clone(),
+                simulation.deterministic,
+            ),
+            // A skipped frame would make the stepped output differ between runs.
+            wait: simulation.deterministic,
+        });
+    }
+}
+
+fn capture_frame(
+    frame_tx: SyncSender<CapturedFrame>,
+    number: u64,
+    size: usize,
+    telemetry: Option<klv::Telemetry>,
+    stats: Arc<FrameStats>,
+    wait: bool,
+) -> Box<dyn FnOnce(Vec<u8>) + Send + Sync> {
+    let requested = Instant::now();
+    Box::new(move |mut data| {
+        // A failed readback still goes to the writer, which fills in for it right away.
+        if data.len() != size {
+            if !data.is_empty() {
+                eprintln!(
+                    "Readback of frame {} has {} bytes, expected {}.",
+                    number,
+                    data.len(),
+                    size
+                );
+            }
+            data = Vec::new();
         } else {
-            eprintln!("No data found in the screenshot.");
+            stats.captured.fetch_add(1, Ordering::Relaxed);
         }
+        let frame = CapturedFrame {
+            number,
+            requested,
+            data: Arc::new(data),
+            telemetry,
+        };
+        if wait {
+            let _ = frame_tx.send(frame);
+        } else {
+            // While the encoder is behind the queue is full and the frame is dropped, the
+            // writer counts it once it gives up waiting for it.
+            let _ = frame_tx.try_send(frame);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    const DELIMITER: [u8; 6] = [0, 0, 0, 1, 0x09, 0xF0];
+    const SLICE: [u8; 7] = [0, 0, 0, 1, 0x65, 0xAA, 0xBB];
+
+    // Hands out the encoder output in the given pieces, then the end of the stream.
+    struct Pieces(VecDeque<Vec<u8>>);
+
+    impl Read for Pieces {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some(piece) = self.0.pop_front() else {
+                return Ok(0);
+            };
+            buf[..piece.len()].copy_from_slice(&piece);
+            Ok(piece.len())
+        }
+    }
+
+    fn access_unit() -> Vec<u8> {
+        [DELIMITER.as_slice(), &SLICE].concat()
+    }
+
+    fn forward(pieces: Vec<Vec<u8>>, metadata_rx: Option<Receiver<Vec<u8>>>) -> Vec<Vec<u8>> {
+        let mut sent = Vec::new();
+        let metadata_rx = metadata_rx.map(|rx| Arc::new(Mutex::new(rx)));
+        forward_access_units(Pieces(pieces.into()), metadata_rx, |access_unit| {
+            sent.push(access_unit.to_vec());
+            true
+        });
+        sent
+    }
+
+    #[test]
+    fn splits_at_each_delimiter() {
+        let output = [access_unit(), access_unit(), access_unit()].concat();
+        assert_eq!(forward(vec![output], None), vec![access_unit(); 3]);
+    }
+
+    #[test]
+    fn splits_output_read_a_byte_at_a_time() {
+        let output = [access_unit(), access_unit()].concat();
+        let pieces = output.iter().map(|&byte| vec![byte]).collect();
+        assert_eq!(forward(pieces, None), vec![access_unit(); 2]);
+    }
+
+    #[test]
+    fn ends_on_a_trailing_zero() {
+        let last = [access_unit(), vec![0]].concat();
+        let output = [access_unit(), last.clone()].concat();
+        assert_eq!(forward(vec![output], None), vec![access_unit(), last]);
+    }
+
+    #[test]
+    fn inserts_telemetry_after_the_delimiter() {
+        let (metadata_tx, metadata_rx) = mpsc::channel();
+        let payload = vec![1, 2, 3];
+        metadata_tx.send(payload.clone()).unwrap();
+        let sent = forward(
+            vec![[access_unit(), access_unit()].concat()],
+            Some(metadata_rx),
+        );
+        let with_sei = [DELIMITER.as_slice(), &klv::sei_nal(&payload), &SLICE].concat();
+        assert_eq!(sent, vec![with_sei, access_unit()]);
     }
 }
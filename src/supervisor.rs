@@ -0,0 +1,231 @@
+use bevy::prelude::*;
+use once_cell::sync::Lazy;
+use std::io::{self, Write};
+use std::process::{Child, ChildStdin};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// A process that ran this long before exiting is restarted after MIN_BACKOFF again.
+const STABLE_TIME: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Every supervised child process, watched by one monitor thread.
+static PROCESSES: Lazy<Mutex<Vec<Arc<Process>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static MONITOR: Once = Once::new();
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+// A child process such as ffmpeg or mediamtx that is restarted whenever it exits.
+pub struct Process {
+    name: String,
+    start: Mutex<Box<dyn FnMut() -> io::Result<Child> + Send>>,
+    state: Mutex<State>,
+    // Apart from the state so health checks never wait on a write to a busy encoder
+    stdin: Mutex<Option<ChildStdin>>,
+}
+
+struct State {
+    child: Option<Child>,
+    started: Instant,
+    started_once: bool,
+    restarts: u32,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+    last_exit: Option<String>,
+}
+
+// Starts a process with start, which spawns the child and takes whatever it needs of its
+// output. The stdin left on the child is what write sends to.
+pub fn spawn(
+    name: impl Into<String>,
+    start: impl FnMut() -> io::Result<Child> + Send + 'static,
+) -> Arc<Process> {
+    let process = Arc::new(Process {
+        name: name.into(),
+        start: Mutex::new(Box::new(start)),
+        state: Mutex::new(State {
+            child: None,
+            started: Instant::now(),
+            started_once: false,
+            restarts: 0,
+            backoff: MIN_BACKOFF,
+            retry_at: None,
+            last_exit: None,
+        }),
+        stdin: Mutex::new(None),
+    });
+    process.start(&mut process.state.lock().unwrap());
+    PROCESSES.lock().unwrap().push(process.clone());
+    MONITOR.call_once(|| {
+        thread::spawn(run_monitor);
+    });
+    process
+}
+
+impl Process {
+    // Writes to the stdin of the running child, false while it is down.
+    pub fn write(&self, data: &[u8]) -> bool {
+        let mut stdin = self.stdin.lock().unwrap();
+        let Some(pipe) = stdin.as_mut() else {
+            return false;
+        };
+        if let Err(e) = pipe.write_all(data) {
+            eprintln!("Failed to write to {}: {}", self.name, e);
+            *stdin = None;
+            drop(stdin);
+            // Stop it for good so the monitor restarts it.
+            if let Some(child) = self.state.lock().unwrap().child.as_mut() {
+                let _ = child.kill();
+            }
+            return false;
+        }
+        true
+    }
+
+    fn start(&self, state: &mut State) {
+        if SHUTTING_DOWN.load(Ordering::Acquire) {
+            return;
+        }
+        let spawned = (self.start.lock().unwrap())();
+        match spawned {
+            Ok(mut child) => {
+                if state.started_once {
+                    state.restarts += 1;
+                    println!("Restarted {} ({} restarts)", self.name, state.restarts);
+                }
+                state.started_once = true;
+                *self.stdin.lock().unwrap() = child.stdin.take();
+                state.child = Some(child);
+                state.started = Instant::now();
+                state.retry_at = None;
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to start {}: {}, retrying in {} s",
+                    self.name,
+                    e,
+                    state.backoff.as_secs()
+                );
+                state.last_exit = Some(e.to_string());
+                self.schedule_restart(state);
+            }
+        }
+    }
+
+    fn schedule_restart(&self, state: &mut State) {
+        state.retry_at = Some(Instant::now() + state.backoff);
+        state.backoff = (state.backoff * 2).min(MAX_BACKOFF);
+    }
+
+    // Restarts the child once it has exited and its backoff has passed.
+    fn check(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(child) = state.child.as_mut() {
+            match child.try_wait() {
+                Ok(None) => return,
+                Ok(Some(status)) => state.last_exit = Some(status.to_string()),
+                Err(e) => state.last_exit = Some(e.to_string()),
+            }
+            state.child = None;
+            *self.stdin.lock().unwrap() = None;
+            if state.started.elapsed() >= STABLE_TIME {
+                state.backoff = MIN_BACKOFF;
+            }
+            eprintln!(
+                "{} stopped ({}), restarting in {} s",
+                self.name,
+                state.last_exit.as_deref().unwrap_or_default(),
+                state.backoff.as_secs()
+            );
+            self.schedule_restart(&mut state);
+        } else if state.retry_at.is_some_and(|at| at <= Instant::now()) {
+            self.start(&mut state);
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state.lock().unwrap().child.is_some()
+    }
+
+    pub fn restarts(&self) -> u32 {
+        self.state.lock().unwrap().restarts
+    }
+
+    // One line on whether the process is up, and for how long or until when it is not.
+    pub fn health(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let status = if state.child.is_some() {
+            format!("running for {} s", state.started.elapsed().as_secs())
+        } else if let Some(at) = state.retry_at {
+            format!(
+                "down ({}), restarting in {} s",
+                state.last_exit.as_deref().unwrap_or_default(),
+                at.saturating_duration_since(Instant::now()).as_secs()
+            )
+        } else {
+            "stopped".to_string()
+        };
+        format!("{} {}, {} restarts", self.name, status, state.restarts)
+    }
+
+    fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        // Closing stdin first lets ffmpeg finish its output, anything still running after
+        // STOP_TIMEOUT is killed. A write still in progress keeps stdin open until then.
+        let had_stdin = match self.stdin.try_lock() {
+            Ok(mut stdin) => stdin.take().is_some(),
+            Err(_) => true,
+        };
+        state.retry_at = None;
+        if let Some(mut child) = state.child.take() {
+            let deadline = Instant::now() + STOP_TIMEOUT;
+            while had_stdin && matches!(child.try_wait(), Ok(None)) && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(50));
+            }
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn run_monitor() {
+    while !SHUTTING_DOWN.load(Ordering::Acquire) {
+        let processes = PROCESSES.lock().unwrap().clone();
+        for process in processes {
+            process.check();
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// Every supervised process, in the order they were started.
+pub fn processes() -> Vec<Arc<Process>> {
+    PROCESSES.lock().unwrap().clone()
+}
+
+pub fn health() -> Vec<String> {
+    processes().iter().map(|process| process.health()).collect()
+}
+
+// Stops every process without restarting it, the servers last.
+pub fn shutdown() {
+    SHUTTING_DOWN.store(true, Ordering::Release);
+    let processes = PROCESSES.lock().unwrap().clone();
+    for process in processes.iter().rev() {
+        process.stop();
+    }
+}
+
+pub fn shutdown_on_exit(mut exit: MessageReader<AppExit>) {
+    if exit.read().next().is_some() {
+        shutdown();
+    }
+}
@@ -0,0 +1,380 @@
+use crate::config;
+use bevy::asset::RenderAssetUsages;
+use bevy::mesh::{Indices, PrimitiveTopology};
+use bevy::prelude::*;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+
+// Samples per side of the terrain mesh, larger DEMs are thinned out.
+const MAX_SAMPLES: usize = 1024;
+// GTRasterTypeGeoKey and its RasterPixelIsPoint value
+const RASTER_TYPE_GEO_KEY: u16 = 1025;
+const RASTER_PIXEL_IS_POINT: u16 = 2;
+
+// Ground elevation on a regular grid, rows from north to south.
+struct Heightmap {
+    columns: usize,
+    rows: usize,
+    cell_size: f64,
+    // Map coordinates of the lower left corner
+    origin: [f64; 2],
+    heights: Vec<f32>,
+}
+
+impl Heightmap {
+    fn from_file(config: &config::TerrainConfig) -> Result<Self, Box<dyn Error>> {
+        let path = Path::new(&config.file);
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let heightmap = match extension.as_str() {
+            "png" => Self::from_png(path, config)?,
+            "tif" | "tiff" => Self::from_geotiff(path)?,
+            "asc" => Self::from_ascii_grid(&fs::read_to_string(path)?)?,
+            _ => return Err(format!("unknown terrain file type .{}", extension).into()),
+        };
+        if heightmap.columns < 2 || heightmap.rows < 2 {
+            return Err("terrain needs at least 2 x 2 samples".into());
+        }
+        if !heightmap.cell_size.is_finite() || heightmap.cell_size <= 0.0 {
+            return Err("terrain cell size must be above 0".into());
+        }
+        Ok(heightmap)
+    }
+
+    // Grey levels between min_height and max_height.
+    fn from_png(path: &Path, config: &config::TerrainConfig) -> Result<Self, Box<dyn Error>> {
+        let image = image::open(path)?.into_luma16();
+        let range = config.max_height - config.min_height;
+        Ok(Self {
+            columns: image.width() as usize,
+            rows: image.height() as usize,
+            cell_size: config.cell_size,
+            origin: [0.0, 0.0],
+            heights: image
+                .pixels()
+                .map(|p| config.min_height + p.0[0] as f32 / u16::MAX as f32 * range)
+                .collect(),
+        })
+    }
+
+    // ESRI ASCII grid, a header of ncols, nrows, xllcorner, yllcorner, cellsize and an
+    // optional NODATA_value followed by the rows.
+    fn from_ascii_grid(text: &str) -> Result<Self, Box<dyn Error>> {
+        let mut tokens = text.split_whitespace().peekable();
+        let (mut columns, mut rows, mut cell_size) = (0, 0, 0.0);
+        let (mut x, mut y, mut centered) = (0.0, 0.0, false);
+        let mut nodata = None;
+        while let Some(key) = tokens.next_if(|t| t.starts_with(|c: char| c.is_ascii_alphabetic())) {
+            let value = tokens.next().ok_or("truncated ASCII grid header")?;
+            match key.to_lowercase().as_str() {
+                "ncols" => columns = value.parse()?,
+                "nrows" => rows = value.parse()?,
+                "xllcorner" => x = value.parse()?,
+                "yllcorner" => y = value.parse()?,
+                "xllcenter" => (x, centered) = (value.parse()?, true),
+                "yllcenter" => y = value.parse()?,
+                "cellsize" => cell_size = value.parse()?,
+                "nodata_value" => nodata = Some(value.parse::<f32>()?),
+                _ => return Err(format!("unknown ASCII grid header {}", key).into()),
+            }
+        }
+        let heights = tokens
+            .map(|t| t.parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()?;
+        if heights.len() != columns * rows {
+            return Err(format!(
+                "expected {} x {} heights, found {}",
+                columns,
+                rows,
+                heights.len()
+            )
+            .into());
+        }
+        if centered {
+            x -= cell_size / 2.0;
+            y -= cell_size / 2.0;
+        }
+        let mut heightmap = Self {
+            columns,
+            rows,
+            cell_size,
+            origin: [x, y],
+            heights,
+        };
+        heightmap.fill_nodata(nodata);
+        Ok(heightmap)
+    }
+
+    // Single band GeoTIFF placed by its pixel scale and tie point, within the default
+    // decoder memory limits.
+    fn from_geotiff(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+        let (columns, rows) = decoder.dimensions()?;
+        let (columns, rows) = (columns as usize, rows as usize);
+        let scale = decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag)?;
+        // Raster I, J, K then model X, Y, Z
+        let tiepoint = decoder.get_tag_f64_vec(Tag::ModelTiepointTag)?;
+        if scale.len() < 2 || tiepoint.len() < 6 {
+            return Err("GeoTIFF without pixel scale or tie point".into());
+        }
+        // Without a raster type the tie point is the corner of its pixel.
+        let pixel_is_point = decoder
+            .get_tag_u16_vec(Tag::GeoKeyDirectoryTag)
+            .is_ok_and(|keys| is_pixel_is_point(&keys));
+        let nodata = decoder
+            .get_tag_ascii_string(Tag::GdalNodata)
+            .ok()
+            .and_then(|s| s.trim_end_matches('\0').trim().parse::<f32>().ok());
+        let heights: Vec<f32> = match decoder.read_image()? {
+            DecodingResult::U8(v) => v.into_iter().map(f32::from).collect(),
+            DecodingResult::U16(v) => v.into_iter().map(f32::from).collect(),
+            DecodingResult::I16(v) => v.into_iter().map(f32::from).collect(),
+            DecodingResult::U32(v) => v.into_iter().map(|h| h as f32).collect(),
+            DecodingResult::I32(v) => v.into_iter().map(|h| h as f32).collect(),
+            DecodingResult::F32(v) => v,
+            DecodingResult::F64(v) => v.into_iter().map(|h| h as f32).collect(),
+            _ => return Err("unsupported GeoTIFF sample format".into()),
+        };
+        if heights.len() != columns * rows {
+            return Err("GeoTIFF must have a single band".into());
+        }
+        let mut left = tiepoint[3] - tiepoint[0] * scale[0];
+        let mut top = tiepoint[4] + tiepoint[1] * scale[1];
+        // A point raster ties the centre of the pixel, its corner is half a cell further out.
+        if pixel_is_point {
+            left -= scale[0] / 2.0;
+            top += scale[1] / 2.0;
+        }
+        let mut heightmap = Self {
+            columns,
+            rows,
+            cell_size: scale[0],
+            origin: [left, top - rows as f64 * scale[1]],
+            heights,
+        };
+        heightmap.fill_nodata(nodata);
+        Ok(heightmap)
+    }
+
+    // Holes take the lowest valid height.
+    fn fill_nodata(&mut self, nodata: Option<f32>) {
+        let valid = |h: f32| h.is_finite() && Some(h) != nodata;
+        let lowest = self
+            .heights
+            .iter()
+            .copied()
+            .filter(|&h| valid(h))
+            .fold(f32::INFINITY, f32::min);
+        let lowest = if lowest.is_finite() { lowest } else { 0.0 };
+        for h in &mut self.heights {
+            if !valid(*h) {
+                *h = lowest;
+            }
+        }
+    }
+
+    fn height(&self, column: usize, row: usize) -> f32 {
+        self.heights[row.min(self.rows - 1) * self.columns + column.min(self.columns - 1)]
+    }
+
+    // Map coordinates of the centre of a sample.
+    fn position(&self, column: usize, row: usize) -> [f64; 2] {
+        [
+            self.origin[0] + (column as f64 + 0.5) * self.cell_size,
+            self.origin[1] + ((self.rows - row) as f64 - 0.5) * self.cell_size,
+        ]
+    }
+
+    // Bilinear elevation at map coordinates, clamped to the edges.
+    fn elevation(&self, x: f64, y: f64) -> f32 {
+        let column =
+            ((x - self.origin[0]) / self.cell_size - 0.5).clamp(0.0, (self.columns - 1) as f64);
+        let row = (self.rows as f64 - (y - self.origin[1]) / self.cell_size - 0.5)
+            .clamp(0.0, (self.rows - 1) as f64);
+        let (c, r) = (column.floor() as usize, row.floor() as usize);
+        let (fc, fr) = ((column - c as f64) as f32, (row - r as f64) as f32);
+        let top = self.height(c, r) * (1.0 - fc) + self.height(c + 1, r) * fc;
+        let bottom = self.height(c, r + 1) * (1.0 - fc) + self.height(c + 1, r + 1) * fc;
+        top * (1.0 - fr) + bottom * fr
+    }
+}
+
+// The GeoKeyDirectory starts with a header of 4 shorts, followed by the keys as an id, the
+// tag holding the value or 0 when it is inline, a count and the value.
+fn is_pixel_is_point(keys: &[u16]) -> bool {
+    keys.chunks_exact(4)
+        .skip(1)
+        .any(|key| key == [RASTER_TYPE_GEO_KEY, 0, 1, RASTER_PIXEL_IS_POINT])
+}
+
+// A DEM placed so the pedestal base is at the origin, with north along -z and east
+// along +x like the azimuth.
+pub struct Terrain {
+    heightmap: Heightmap,
+    location: [f64; 2],
+    base: f32,
+}
+
+impl Terrain {
+    pub fn load(config: &config::TerrainConfig) -> Result<Self, Box<dyn Error>> {
+        let heightmap = Heightmap::from_file(config)?;
+        let location = config.location.unwrap_or([
+            heightmap.origin[0] + heightmap.columns as f64 * heightmap.cell_size / 2.0,
+            heightmap.origin[1] + heightmap.rows as f64 * heightmap.cell_size / 2.0,
+        ]);
+        let base = config
+            .height
+            .unwrap_or_else(|| heightmap.elevation(location[0], location[1]));
+        Ok(Self {
+            heightmap,
+            location,
+            base,
+        })
+    }
+
+    // Height of the ground at x, z in the world.
+    pub fn height_at(&self, x: f32, z: f32) -> f32 {
+        let elevation = self
+            .heightmap
+            .elevation(self.location[0] + x as f64, self.location[1] - z as f64);
+        elevation - self.base
+    }
+
+    pub fn lowest(&self) -> f32 {
+        self.heightmap
+            .heights
+            .iter()
+            .copied()
+            .fold(f32::INFINITY, f32::min)
+            - self.base
+    }
+
+    pub fn mesh(&self) -> Mesh {
+        let heightmap = &self.heightmap;
+        let stride = heightmap.columns.max(heightmap.rows).div_ceil(MAX_SAMPLES);
+        // The last row and column are always included so the mesh covers the whole DEM.
+        let samples = |count: usize| -> Vec<usize> {
+            let mut samples: Vec<usize> = (0..count).step_by(stride).collect();
+            if samples.last() != Some(&(count - 1)) {
+                samples.push(count - 1);
+            }
+            samples
+        };
+        let (columns, rows) = (samples(heightmap.columns), samples(heightmap.rows));
+
+        let mut positions = Vec::with_capacity(columns.len() * rows.len());
+        let mut uvs = Vec::with_capacity(columns.len() * rows.len());
+        for &row in &rows {
+            for &column in &columns {
+                let [x, y] = heightmap.position(column, row);
+                positions.push([
+                    (x - self.location[0]) as f32,
+                    heightmap.height(column, row) - self.base,
+                    (self.location[1] - y) as f32,
+                ]);
+                uvs.push([
+                    column as f32 / (heightmap.columns - 1) as f32,
+                    row as f32 / (heightmap.rows - 1) as f32,
+                ]);
+            }
+        }
+        let width = columns.len() as u32;
+        let mut indices = Vec::with_capacity((columns.len() - 1) * (rows.len() - 1) * 6);
+        for r in 0..rows.len() as u32 - 1 {
+            for c in 0..width - 1 {
+                let i = r * width + c;
+                indices.extend([i, i + width, i + 1, i + 1, i + width, i + width + 1]);
+            }
+        }
+        Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+        .with_inserted_indices(Indices::U32(indices))
+        .with_computed_smooth_normals()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRID: &str = "ncols 3
+nrows 2
+xllcorner 1000
+yllcorner 2000
+cellsize 10
+NODATA_value -9999
+1 2 3
+4 -9999 6
+";
+
+    #[test]
+    fn ascii_grid_origin_is_the_lower_left_corner() {
+        let heightmap = Heightmap::from_ascii_grid(GRID).unwrap();
+        assert_eq!((heightmap.columns, heightmap.rows), (3, 2));
+        assert_eq!(heightmap.cell_size, 10.0);
+        assert_eq!(heightmap.origin, [1000.0, 2000.0]);
+    }
+
+    #[test]
+    fn ascii_grid_xllcenter_moves_the_origin_half_a_cell() {
+        let grid = GRID
+            .replace("xllcorner 1000", "xllcenter 1005")
+            .replace("yllcorner 2000", "yllcenter 2005");
+        let heightmap = Heightmap::from_ascii_grid(&grid).unwrap();
+        assert_eq!(heightmap.origin, [1000.0, 2000.0]);
+    }
+
+    #[test]
+    fn first_row_is_north() {
+        let heightmap = Heightmap::from_ascii_grid(GRID).unwrap();
+        assert_eq!(heightmap.position(0, 0), [1005.0, 2015.0]);
+        assert_eq!(heightmap.position(2, 1), [1025.0, 2005.0]);
+        assert_eq!(heightmap.elevation(1005.0, 2015.0), 1.0);
+        assert_eq!(heightmap.elevation(1025.0, 2005.0), 6.0);
+    }
+
+    #[test]
+    fn nodata_takes_the_lowest_height() {
+        let heightmap = Heightmap::from_ascii_grid(GRID).unwrap();
+        assert_eq!(heightmap.heights, vec![1.0, 2.0, 3.0, 4.0, 1.0, 6.0]);
+    }
+
+    #[test]
+    fn elevation_is_bilinear_and_clamped() {
+        let heightmap = Heightmap::from_ascii_grid(GRID).unwrap();
+        // Halfway between the samples 1, 2, 4 and 1
+        assert_eq!(heightmap.elevation(1010.0, 2010.0), 2.0);
+        // A quarter of the way from 2 to 3 on the north row
+        assert_eq!(heightmap.elevation(1017.5, 2015.0), 2.25);
+        // Past the edges the nearest sample holds
+        assert_eq!(heightmap.elevation(900.0, 3000.0), 1.0);
+        assert_eq!(heightmap.elevation(1100.0, 1900.0), 6.0);
+    }
+
+    #[test]
+    fn ascii_grid_needs_every_height() {
+        assert!(Heightmap::from_ascii_grid(&GRID.replace("4 -9999 6", "4 5")).is_err());
+    }
+
+    #[test]
+    fn geotiff_raster_type_is_read_from_the_geo_keys() {
+        // Model type projected, raster type point and a projected CRS
+        let point = [1, 1, 0, 3, 1024, 0, 1, 1, 1025, 0, 1, 2, 3072, 0, 1, 32633];
+        assert!(is_pixel_is_point(&point));
+        let area = [1, 1, 0, 2, 1024, 0, 1, 1, 1025, 0, 1, 1];
+        assert!(!is_pixel_is_point(&area));
+        // The header is not a key
+        assert!(!is_pixel_is_point(&[1025, 0, 1, 2]));
+    }
+}
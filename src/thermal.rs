@@ -0,0 +1,166 @@
+use crate::config;
+use crate::radar_cam;
+use bevy::camera::visibility::RenderLayers;
+use bevy::core_pipeline::tonemapping::Tonemapping;
+use bevy::light::NotShadowCaster;
+use bevy::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// Thermal twins of every mesh live on this layer, only the thermal camera renders it.
+const THERMAL_LAYER: usize = 1;
+
+// Surface temperature in degrees Celsius, inherited by all descendant meshes.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct Temperature(pub f32);
+
+#[derive(Component)]
+pub struct ThermalCamera;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Palette {
+    #[default]
+    WhiteHot,
+    BlackHot,
+}
+
+impl Palette {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().replace('-', "_").as_str() {
+            "white_hot" | "whitehot" => Some(Palette::WhiteHot),
+            "black_hot" | "blackhot" => Some(Palette::BlackHot),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct Thermal {
+    pub palette: Palette,
+    pub min_temperature: f32,
+    pub max_temperature: f32,
+    pub ambient_temperature: f32,
+    // One material per temperature in tenths of a degree.
+    materials: HashMap<i32, Handle<StandardMaterial>>,
+    applied_palette: Palette,
+}
+
+impl Thermal {
+    pub fn new(config: config::ThermalConfig) -> Self {
+        Self {
+            palette: config.palette,
+            min_temperature: config.min_temperature,
+            max_temperature: config.max_temperature,
+            ambient_temperature: config.ambient_temperature,
+            materials: HashMap::new(),
+            applied_palette: config.palette,
+        }
+    }
+
+    fn color(&self, temperature: f32) -> Color {
+        let range = (self.max_temperature - self.min_temperature).max(f32::EPSILON);
+        let heat = ((temperature - self.min_temperature) / range).clamp(0.0, 1.0);
+        let level = match self.palette {
+            Palette::WhiteHot => heat,
+            Palette::BlackHot => 1.0 - heat,
+        };
+        Color::srgb(level, level, level)
+    }
+
+    fn material(
+        &mut self,
+        temperature: f32,
+        materials: &mut Assets<StandardMaterial>,
+    ) -> Handle<StandardMaterial> {
+        let key = (temperature * 10.0).round() as i32;
+        let color = self.color(key as f32 / 10.0);
+        self.materials
+            .entry(key)
+            .or_insert_with(|| {
+                materials.add(StandardMaterial {
+                    base_color: color,
+                    unlit: true,
+                    ..default()
+                })
+            })
+            .clone()
+    }
+}
+
+pub fn spawn_thermal_cam(
+    commands: &mut Commands,
+    images: &mut Assets<Image>,
+    pivot: Entity,
+    config: &config::CameraConfig,
+) -> (Handle<Image>, Entity) {
+    let image_handle = radar_cam::render_target(images, config.width, config.height);
+    let camera = commands
+        .spawn((
+            Transform::from_translation(Vec3::from_array(config.mount_offset)),
+            Camera3d::default(),
+            Camera {
+                target: image_handle.clone().into(),
+                order: 1,
+                clear_color: ClearColorConfig::Custom(Color::BLACK),
+                ..default()
+            },
+            Projection::Perspective(PerspectiveProjection {
+                fov: config.vertical_fov.to_radians(),
+                aspect_ratio: config.width as f32 / config.height as f32,
+                near: 0.1,
+                far: 1000.0,
+            }),
+            // Keep the palette levels as they are.
+            Tonemapping::None,
+            RenderLayers::layer(THERMAL_LAYER),
+            ThermalCamera,
+            Visibility::Visible,
+        ))
+        .id();
+    commands.entity(pivot).add_child(camera);
+    (image_handle, camera)
+}
+
+// Gives every new mesh a child copy on the thermal layer drawn with the heat palette.
+#[allow(clippy::type_complexity)]
+pub fn spawn_thermal_twins(
+    mut commands: Commands,
+    mut thermal: ResMut<Thermal>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    meshes: Query<(Entity, &Mesh3d), (Added<Mesh3d>, Without<radar_cam::Twin>)>,
+    parents: Query<&ChildOf>,
+    temperatures: Query<&Temperature>,
+) {
+    for (entity, mesh) in meshes.iter() {
+        let temperature = std::iter::once(entity)
+            .chain(parents.iter_ancestors(entity))
+            .find_map(|e| temperatures.get(e).ok())
+            .map(|t| t.0)
+            .unwrap_or(thermal.ambient_temperature);
+        let material = thermal.material(temperature, &mut materials);
+        commands.entity(entity).with_child((
+            Mesh3d(mesh.0.clone()),
+            MeshMaterial3d(material),
+            Transform::IDENTITY,
+            RenderLayers::layer(THERMAL_LAYER),
+            NotShadowCaster,
+            radar_cam::Twin,
+        ));
+    }
+}
+
+pub fn update_palette(
+    mut thermal: ResMut<Thermal>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    if thermal.palette == thermal.applied_palette {
+        return;
+    }
+    thermal.applied_palette = thermal.palette;
+    for (key, handle) in thermal.materials.iter() {
+        if let Some(material) = materials.get_mut(handle) {
+            material.base_color = thermal.color(*key as f32 / 10.0);
+        }
+    }
+}
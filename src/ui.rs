@@ -1,13 +1,22 @@
+use crate::metrics;
 use crate::radar;
 use crate::radar_cam;
+use crate::recording;
+use crate::simulation;
 use crate::stream;
 use bevy::prelude::*;
 use bevy_egui::{egui, EguiContexts};
 
+#[allow(clippy::too_many_arguments)]
 pub fn ui_system(
     mut contexts: EguiContexts,
     framebuffer: Res<stream::FrameBuffer>,
     radar_state: Res<radar::Radar>,
+    mut zoom: ResMut<radar_cam::Zoom>,
+    mut recorder: ResMut<recording::Recorder>,
+    mut simulation: ResMut<simulation::Simulation>,
+    time: Res<Time>,
+    streams: Query<&stream::CameraStream>,
     query: Query<&Projection, With<radar_cam::RadarCamera>>,
 ) {
     let Ok(ctx) = contexts.ctx_mut() else {
@@ -57,6 +66,8 @@ pub fn ui_system(
                 ui.label(format!("Far plane: {:>6.2}", far));
                 ui.label(format!("Near plane: {:>6.2}", near));
                 ui.label(format!("Aspect ratio: {:>6.2}", aspect_ratio));
+                let range = zoom.min_fov..=zoom.max_fov;
+                ui.add(egui::Slider::new(&mut zoom.target_fov, range).text("Zoom fov"));
             });
         });
 
@@ -82,6 +93,50 @@ pub fn ui_system(
                     "Target elevation: {:>6.2}",
                     radar_state.target.elevation
                 ));
+                ui.separator();
+                ui.label(format!("Sim time: {}", simulation.describe(time.elapsed())));
+                if !simulation.deterministic {
+                    let label = if simulation.paused { "Resume" } else { "Pause" };
+                    if ui.button(label).clicked() {
+                        simulation.paused = !simulation.paused;
+                    }
+                    let range = simulation::MIN_SPEED..=simulation::MAX_SPEED;
+                    ui.add(
+                        egui::Slider::new(&mut simulation.speed, range)
+                            .logarithmic(true)
+                            .text("Speed"),
+                    );
+                }
+                ui.separator();
+                match recorder.video_path().cloned() {
+                    Some(path) => {
+                        ui.label(format!("Recording to {}", path.display()));
+                        if ui.button("Stop recording").clicked() {
+                            recorder.stop(false);
+                        }
+                    }
+                    None => {
+                        if ui.button("Start recording").clicked() {
+                            if let Err(e) = recorder.start() {
+                                eprintln!("Failed to start recording: {}", e);
+                            }
+                        }
+                    }
+                }
             });
         });
+
+    egui::Window::new("Metrics")
+        .default_open(false)
+        .show(ctx, |ui| {
+            for stream in &streams {
+                ui.label(format!(
+                    "Stream {} frame: {}",
+                    stream.path, stream.frame_number
+                ));
+            }
+            for line in metrics::summary() {
+                ui.label(line);
+            }
+        });
 }